@@ -0,0 +1,673 @@
+use std::{
+    collections::{BTreeSet, HashMap, HashSet},
+    ops::RangeInclusive,
+};
+use log::{debug, warn};
+use oxc_allocator::Allocator;
+use oxc_ast::ast::{
+    AssignmentExpression, AssignmentOperator, AssignmentTarget, BindingPattern, CallExpression,
+    Expression, Function, FunctionType, LogicalExpression, LogicalOperator, Statement,
+    VariableDeclarator,
+};
+use oxc_ast_visit::{walk, Visit};
+use oxc_parser::Parser;
+use oxc_semantic::SemanticBuilder;
+use oxc_span::{GetSpan, SourceType, Span};
+use oxc_syntax::scope::ScopeFlags;
+
+use crate::consts::{JS_GLOBALS, MAX_DEPENDENCIES, NSIG_FUNCTION_NAME};
+
+/// The functions the parser found in a player JS
+pub struct ParsedPlayer {
+    pub nsig_function_name: Option<String>,
+    /// The nsig function, declared as `decrypt_nsig`
+    pub nsig_function_code: Option<String>,
+    /// The name and the code of the sig function, with its helper object
+    pub sig_function: Option<(String, String)>,
+    pub declarations: Declarations,
+}
+
+/// Declarations the extracted code depends on
+#[derive(Default)]
+pub struct Dependencies {
+    /// The declarations to evaluate before the code, in dependency order
+    pub code: String,
+    /// Names used by the code that are neither declared by the player nor globals
+    pub unresolved: BTreeSet<String>,
+}
+
+/// A value assigned to a name in the player JS
+enum Value {
+    /// Source of the parameters and the body of a function, and its number
+    /// of parameters
+    Function(Span, usize),
+    /// The names in an array of identifiers, `None` for other elements
+    Array(Vec<Option<String>>),
+    Object(Span),
+    Other,
+}
+
+/// The function called on the `n` parameter
+enum NsigCallee {
+    Name(String),
+    ArrayElement(String, usize),
+}
+
+struct SigCandidate {
+    name: String,
+    helper_object: String,
+    function: Span,
+}
+
+/// Where a name gets its value
+struct Declaration {
+    /// Function nesting of the declaration
+    depth: usize,
+    value: Value,
+    /// The declared value, or the whole function declaration
+    source: Span,
+    is_function_declaration: bool,
+    /// Position of the `}` closing the function the declaration is in, or the
+    /// end of the player JS
+    scope_end: u32,
+}
+
+/// The outermost declaration of every name in the player JS
+#[derive(Default)]
+pub struct Declarations(HashMap<String, Declaration>);
+
+#[derive(Default)]
+struct Collector {
+    /// Function nesting of the code being visited
+    depth: usize,
+    /// Where the bodies of the functions being visited end, innermost last
+    scope_ends: Vec<u32>,
+    values: Declarations,
+    nsig_callees: Vec<NsigCallee>,
+    sig_candidates: Vec<SigCandidate>,
+}
+
+/// `(a){...}` of a function
+fn function_span(function: &Function) -> Option<Span> {
+    let body = function.body.as_ref()?;
+    Some(Span::new(function.params.span.start, body.span.end))
+}
+
+fn identifier_name<'b>(expression: &'b Expression) -> Option<&'b str> {
+    match expression.without_parentheses() {
+        Expression::Identifier(x) => Some(x.name.as_str()),
+        _ => None,
+    }
+}
+
+/// Name of the object a method is called on, `H` for `H.x(...)` and `H[x](...)`
+fn member_object_name<'b>(expression: &'b Expression) -> Option<&'b str> {
+    match expression.without_parentheses() {
+        Expression::StaticMemberExpression(x) => identifier_name(&x.object),
+        Expression::ComputedMemberExpression(x) => identifier_name(&x.object),
+        _ => None,
+    }
+}
+
+/// Whether `expression` calls a method of `name`, with `name` as first argument
+/// if `first_argument` is given
+fn calls_method_of(expression: &Expression, name: &str, first_argument: Option<&str>) -> bool {
+    let call = match expression.without_parentheses() {
+        Expression::CallExpression(x) => x,
+        _ => return false,
+    };
+    if member_object_name(&call.callee) != Some(name) {
+        return false;
+    }
+    match first_argument {
+        None => true,
+        Some(argument) => {
+            call.arguments.first().and_then(|x| x.as_expression()).and_then(identifier_name)
+                == Some(argument)
+        }
+    }
+}
+
+/// Name of the helper object if `function` has the shape of the sig function:
+/// `a=a.split("");H.x(a,1);H.y(a,2);return a.join("")`
+fn sig_helper_object(function: &Function) -> Option<String> {
+    if function.params.items.len() != 1 {
+        return None;
+    }
+    let param = function.params.items[0].pattern.get_identifier_name()?;
+    let param = param.as_str();
+    let statements = &function.body.as_ref()?.statements;
+    if statements.len() < 3 {
+        return None;
+    }
+
+    // a=a.split("")
+    match &statements[0] {
+        Statement::ExpressionStatement(x) => match &x.expression {
+            Expression::AssignmentExpression(x) => {
+                let target = x.left.get_identifier_name();
+                if target != Some(param) || !calls_method_of(&x.right, param, None) {
+                    return None;
+                }
+            }
+            _ => return None,
+        },
+        _ => return None,
+    }
+    // return a.join("")
+    match &statements[statements.len() - 1] {
+        Statement::ReturnStatement(x) => match &x.argument {
+            Some(x) if calls_method_of(x, param, None) => {}
+            _ => return None,
+        },
+        _ => return None,
+    }
+
+    // H.x(a,1), every call to the same object
+    let mut helper_object: Option<&str> = None;
+    for statement in &statements[1..statements.len() - 1] {
+        let expression = match statement {
+            Statement::ExpressionStatement(x) => &x.expression,
+            _ => return None,
+        };
+        let calls: Vec<&Expression> = match expression.without_parentheses() {
+            Expression::SequenceExpression(x) => x.expressions.iter().collect(),
+            x => vec![x],
+        };
+        for call in calls {
+            let object = match call.without_parentheses() {
+                Expression::CallExpression(x) => member_object_name(&x.callee)?,
+                _ => return None,
+            };
+            if helper_object.is_some_and(|x| x != object) {
+                return None;
+            }
+            if !calls_method_of(call, object, Some(param)) {
+                return None;
+            }
+            helper_object = Some(object);
+        }
+    }
+    helper_object.map(|x| x.to_string())
+}
+
+/// Whether `expression` contains a `.get(...)` call, as in `b=a.get("n")`
+fn contains_get_call(expression: &Expression) -> bool {
+    #[derive(Default)]
+    struct Finder(bool);
+    impl<'a> Visit<'a> for Finder {
+        fn visit_call_expression(&mut self, it: &CallExpression<'a>) {
+            if let Expression::StaticMemberExpression(x) = &it.callee {
+                if x.property.name == "get" {
+                    self.0 = true;
+                    return;
+                }
+            }
+            walk::walk_call_expression(self, it);
+        }
+    }
+    let mut finder = Finder::default();
+    finder.visit_expression(expression);
+    finder.0
+}
+
+/// The callee of `b=F(c)` or `b=F[0](c)`
+fn nsig_callee(assignment: &AssignmentExpression) -> Option<NsigCallee> {
+    if assignment.operator != AssignmentOperator::Assign {
+        return None;
+    }
+    if !matches!(assignment.left, AssignmentTarget::AssignmentTargetIdentifier(_)) {
+        return None;
+    }
+    let call = match assignment.right.without_parentheses() {
+        Expression::CallExpression(x) => x,
+        _ => return None,
+    };
+    if call.arguments.len() != 1 {
+        return None;
+    }
+    call.arguments[0].as_expression().and_then(identifier_name)?;
+    match call.callee.without_parentheses() {
+        Expression::Identifier(x) => Some(NsigCallee::Name(x.name.to_string())),
+        Expression::ComputedMemberExpression(x) => {
+            let array = identifier_name(&x.object)?;
+            let index = match &x.expression {
+                Expression::NumericLiteral(x) => x.value as usize,
+                _ => return None,
+            };
+            Some(NsigCallee::ArrayElement(array.to_string(), index))
+        }
+        _ => None,
+    }
+}
+
+impl Collector {
+    fn add_value(&mut self, name: &str, value_expression: &Expression) {
+        let value = match value_expression.without_parentheses() {
+            Expression::FunctionExpression(function) => {
+                if let (Some(helper_object), Some(span)) =
+                    (sig_helper_object(function), function_span(function))
+                {
+                    self.sig_candidates.push(SigCandidate {
+                        name: name.to_string(),
+                        helper_object,
+                        function: span,
+                    });
+                }
+                match function_span(function) {
+                    Some(x) => Value::Function(x, function.params.items.len()),
+                    None => Value::Other,
+                }
+            }
+            Expression::ArrayExpression(array) => Value::Array(
+                array
+                    .elements
+                    .iter()
+                    .map(|x| x.as_expression().and_then(identifier_name).map(String::from))
+                    .collect(),
+            ),
+            Expression::ObjectExpression(object) => Value::Object(object.span),
+            _ => Value::Other,
+        };
+        self.values.insert(
+            name,
+            Declaration {
+                depth: self.depth,
+                value,
+                source: value_expression.span(),
+                is_function_declaration: false,
+                scope_end: self.scope_end(),
+            },
+        );
+    }
+
+    fn scope_end(&self) -> u32 {
+        self.scope_ends.last().copied().unwrap_or_default()
+    }
+
+    fn function(&self, name: &str) -> Option<Span> {
+        match self.values.0.get(name) {
+            Some(Declaration {
+                value: Value::Function(x, _),
+                ..
+            }) => Some(*x),
+            _ => None,
+        }
+    }
+
+    fn resolve_nsig(&self, callee: &NsigCallee) -> Option<(String, Span)> {
+        let name = match callee {
+            NsigCallee::Name(x) => x.clone(),
+            NsigCallee::ArrayElement(array, index) => match self.values.0.get(array) {
+                Some(Declaration {
+                    value: Value::Array(x),
+                    ..
+                }) => x.get(*index)?.clone()?,
+                _ => return None,
+            },
+        };
+        let span = self.function(&name)?;
+        Some((name, span))
+    }
+}
+
+impl<'a> Visit<'a> for Collector {
+    fn visit_function(&mut self, it: &Function<'a>, flags: ScopeFlags) {
+        if it.r#type == FunctionType::FunctionDeclaration {
+            if let (Some(id), Some(span)) = (&it.id, function_span(it)) {
+                let name = id.name.as_str();
+                if let Some(helper_object) = sig_helper_object(it) {
+                    self.sig_candidates.push(SigCandidate {
+                        name: name.to_string(),
+                        helper_object,
+                        function: span,
+                    });
+                }
+                self.values.insert(
+                    name,
+                    Declaration {
+                        depth: self.depth,
+                        value: Value::Function(span, it.params.items.len()),
+                        source: it.span,
+                        is_function_declaration: true,
+                        scope_end: self.scope_end(),
+                    },
+                );
+            }
+        }
+        let body_end = it.body.as_ref().map(|x| x.span.end.saturating_sub(1));
+        self.depth += 1;
+        if let Some(x) = body_end {
+            self.scope_ends.push(x);
+        }
+        walk::walk_function(self, it, flags);
+        if body_end.is_some() {
+            self.scope_ends.pop();
+        }
+        self.depth -= 1;
+    }
+
+    fn visit_variable_declarator(&mut self, it: &VariableDeclarator<'a>) {
+        if let (BindingPattern::BindingIdentifier(id), Some(init)) = (&it.id, &it.init) {
+            self.add_value(id.name.as_str(), init);
+        }
+        walk::walk_variable_declarator(self, it);
+    }
+
+    fn visit_assignment_expression(&mut self, it: &AssignmentExpression<'a>) {
+        if it.operator == AssignmentOperator::Assign {
+            if let AssignmentTarget::AssignmentTargetIdentifier(id) = &it.left {
+                self.add_value(id.name.as_str(), &it.right);
+            }
+        }
+        walk::walk_assignment_expression(self, it);
+    }
+
+    fn visit_logical_expression(&mut self, it: &LogicalExpression<'a>) {
+        // (b=a.get("n"))&&(b=F[0](b),a.set("n",b))
+        if it.operator == LogicalOperator::And {
+            let assignments: Vec<&Expression> = match it.right.without_parentheses() {
+                Expression::SequenceExpression(x) => x.expressions.iter().collect(),
+                x => vec![x],
+            };
+            for assignment in assignments {
+                if let Expression::AssignmentExpression(x) = assignment.without_parentheses() {
+                    if let Some(callee) = nsig_callee(x) {
+                        if contains_get_call(&it.left) {
+                            self.nsig_callees.push(callee);
+                        }
+                    }
+                }
+            }
+        }
+        walk::walk_logical_expression(self, it);
+    }
+}
+
+impl Declarations {
+    /// Keeps the outermost declaration of `name`
+    fn insert(&mut self, name: &str, declaration: Declaration) {
+        let is_outer = match self.0.get(name) {
+            Some(x) => declaration.depth < x.depth,
+            None => true,
+        };
+        if is_outer {
+            self.0.insert(name.to_string(), declaration);
+        }
+    }
+
+    /// Code declaring `name`, if the player declares it outside of its functions
+    fn code(&self, player_javascript: &str, name: &str) -> Option<String> {
+        let declaration = self.0.get(name)?;
+        // the player wraps all of its code in one function
+        if declaration.depth > 1 {
+            return None;
+        }
+        let source = source(player_javascript, declaration.source);
+        match declaration.is_function_declaration {
+            true => Some(format!("{};", source)),
+            false => Some(format!("var {}={};", name, source)),
+        }
+    }
+
+    /// Names and sources of the functions taking one argument that the player
+    /// declares outside of its functions, as `(a){...}`, largest first
+    pub fn one_argument_functions<'b>(
+        &self,
+        player_javascript: &'b str,
+        sizes: RangeInclusive<usize>,
+    ) -> Vec<(String, &'b str)> {
+        let mut functions: Vec<(&String, Span)> = self
+            .0
+            .iter()
+            .filter_map(|(name, declaration)| match declaration.value {
+                Value::Function(span, 1) if declaration.depth <= 1 => Some((name, span)),
+                _ => None,
+            })
+            .filter(|(_, span)| sizes.contains(&(span.size() as usize)))
+            .collect();
+        functions.sort_by_key(|(name, span)| (std::cmp::Reverse(span.size()), name.as_str()));
+        functions
+            .into_iter()
+            .map(|(name, span)| (name.clone(), source(player_javascript, span)))
+            .collect()
+    }
+
+    /// The player JS with `globalThis.{alias}={name};` at the end of the
+    /// function declaring each name, so running the player exports them
+    pub fn export(&self, player_javascript: &str, exports: &[(&str, &str)]) -> Result<String, String> {
+        let mut insertions: Vec<(usize, String)> = Vec::with_capacity(exports.len());
+        for (alias, name) in exports {
+            let declaration = match self.0.get(*name) {
+                Some(x) => x,
+                None => return Err(format!("{} is not declared", name)),
+            };
+            insertions.push((
+                declaration.scope_end as usize,
+                format!("\n;globalThis.{}={};", alias, name),
+            ));
+        }
+        insertions.sort();
+        let mut code = String::with_capacity(player_javascript.len() + 64 * insertions.len());
+        let mut position = 0;
+        for (end, hook) in insertions {
+            code += &player_javascript[position..end];
+            code += &hook;
+            position = end;
+        }
+        code += &player_javascript[position..];
+        Ok(code)
+    }
+
+    /// Finds the names `code` uses without declaring them and pulls in their
+    /// declarations from the player JS, and the names these use, recursively
+    pub fn resolve(&self, player_javascript: &str, code: &str) -> Result<Dependencies, String> {
+        let mut dependencies = Dependencies::default();
+        let (names, declared) = free_names(code)?;
+        // names the code declares itself, e.g. the global array prepended by a fixup
+        let mut seen: HashSet<String> = declared.into_iter().collect();
+        // names with the code declaring them, their own dependencies are added before it
+        let mut stack: Vec<(String, Option<String>)> = names
+            .into_iter()
+            .rev()
+            .map(|x| (x, None))
+            .collect();
+        let mut count = 0;
+        while let Some((name, declaration)) = stack.pop() {
+            if let Some(x) = declaration {
+                dependencies.code += &x;
+                continue;
+            }
+            if !seen.insert(name.clone()) {
+                continue;
+            }
+            let declaration = match self.code(player_javascript, &name) {
+                Some(x) => x,
+                None => {
+                    if !JS_GLOBALS.contains(&name.as_str()) {
+                        dependencies.unresolved.insert(name);
+                    }
+                    continue;
+                }
+            };
+            count += 1;
+            if count > MAX_DEPENDENCIES {
+                warn!("Extracted code has more than {} dependencies", MAX_DEPENDENCIES);
+                dependencies.unresolved.insert(name);
+                continue;
+            }
+            debug!("Extracted code depends on {}", name);
+            let (names, _) = free_names(&declaration)?;
+            stack.push((name, Some(declaration)));
+            stack.extend(names.into_iter().rev().map(|x| (x, None)));
+        }
+        Ok(dependencies)
+    }
+}
+
+fn source(player_javascript: &str, span: Span) -> &str {
+    &player_javascript[span.start as usize..span.end as usize]
+}
+
+fn parse_error(error: Option<String>) -> String {
+    error.unwrap_or_else(|| "parser gave up".to_string())
+}
+
+/// The names `code` uses without declaring them, in order of appearance, and
+/// the names it declares at its top level
+fn free_names(code: &str) -> Result<(Vec<String>, Vec<String>), String> {
+    let allocator = Allocator::default();
+    let parsed = Parser::new(&allocator, code, SourceType::cjs()).parse();
+    if parsed.panicked || !parsed.errors.is_empty() {
+        return Err(parse_error(parsed.errors.first().map(|x| x.to_string())));
+    }
+    let semantic = SemanticBuilder::new().build(&parsed.program).semantic;
+    let scoping = semantic.scoping();
+    let mut names: Vec<(u32, String)> = scoping
+        .root_unresolved_references()
+        .iter()
+        .map(|(name, references)| {
+            let first = references
+                .iter()
+                .map(|x| semantic.nodes().get_node(scoping.get_reference(*x).node_id()).span().start)
+                .min()
+                .unwrap_or_default();
+            (first, name.to_string())
+        })
+        .collect();
+    names.sort();
+    let declared = scoping
+        .get_bindings(scoping.root_scope_id())
+        .keys()
+        .map(|x| x.to_string())
+        .collect();
+    Ok((names.into_iter().map(|(_, x)| x).collect(), declared))
+}
+
+/// Finds the nsig and sig functions with a JavaScript parser. Fails if the
+/// player JS cannot be parsed, functions that are not found are `None`.
+pub fn parse_player(player_javascript: &str) -> Result<ParsedPlayer, String> {
+    let allocator = Allocator::default();
+    let parsed = Parser::new(&allocator, player_javascript, SourceType::cjs()).parse();
+    if parsed.panicked || !parsed.errors.is_empty() {
+        return Err(parse_error(parsed.errors.first().map(|x| x.to_string())));
+    }
+
+    let mut collector = Collector {
+        scope_ends: vec![parsed.program.span.end],
+        ..Default::default()
+    };
+    collector.visit_program(&parsed.program);
+    let source = |span: Span| source(player_javascript, span);
+
+    let nsig_function = collector
+        .nsig_callees
+        .iter()
+        .find_map(|x| collector.resolve_nsig(x));
+    if let Some((name, _)) = &nsig_function {
+        debug!("Parser found nsig function {}", name);
+    }
+    let nsig_function_code = nsig_function
+        .as_ref()
+        .map(|(_, span)| format!("function {}{};", NSIG_FUNCTION_NAME, source(*span)));
+
+    let sig_function = collector.sig_candidates.iter().find_map(|candidate| {
+        let helper_object = match collector.values.0.get(&candidate.helper_object) {
+            Some(Declaration {
+                value: Value::Object(x),
+                ..
+            }) => *x,
+            _ => return None,
+        };
+        debug!(
+            "Parser found sig function {} with helper object {}",
+            candidate.name, candidate.helper_object
+        );
+        let code = format!(
+            "var {};var {}={};{}=function{}",
+            candidate.name,
+            candidate.helper_object,
+            source(helper_object),
+            candidate.name,
+            source(candidate.function)
+        );
+        Some((candidate.name.clone(), code))
+    });
+
+    Ok(ParsedPlayer {
+        nsig_function_name: nsig_function.map(|(name, _)| name),
+        nsig_function_code,
+        sig_function,
+        declarations: collector.values,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    static PLAYER: &str = r#"var _yt_player={};(function(g){var window=this;
+var Wc=["split","join"];
+var Lx={zP:function(a,b){a.splice(0,b)},Vh:function(a){a.reverse()}};
+var mn=function(a){a=a.split("");Lx.Vh(a,0);Lx.zP(a,2);return a.join("")};
+function rot(a,b){return a.slice(b)+a.slice(0,b)}
+var Xy=function(a){var b=a[Wc[0]]("");return rot(b[Wc[1]](""),3)};
+var Fq=[Xy];
+var Nr=function(a){var b;(b=a.get("n"))&&(b=Fq[0](b),a.set("n",b))};
+})(_yt_player);
+"#;
+
+    #[test]
+    fn finds_nsig_function_through_array() {
+        let parsed = parse_player(PLAYER).unwrap();
+        assert_eq!(parsed.nsig_function_name.as_deref(), Some("Xy"));
+        assert_eq!(
+            parsed.nsig_function_code.as_deref(),
+            Some(r#"function decrypt_nsig(a){var b=a[Wc[0]]("");return rot(b[Wc[1]](""),3)};"#)
+        );
+    }
+
+    #[test]
+    fn finds_sig_function_with_helper_object() {
+        let (name, code) = parse_player(PLAYER).unwrap().sig_function.unwrap();
+        assert_eq!(name, "mn");
+        assert!(code.starts_with("var mn;var Lx={zP:function(a,b)"));
+        assert!(code.ends_with(r#"mn=function(a){a=a.split("");Lx.Vh(a,0);Lx.zP(a,2);return a.join("")}"#));
+    }
+
+    #[test]
+    fn rejects_invalid_javascript() {
+        assert!(parse_player("var a=function(){").is_err());
+    }
+
+    #[test]
+    fn resolves_dependencies() {
+        let parsed = parse_player(PLAYER).unwrap();
+        let code = parsed.nsig_function_code.unwrap();
+        let dependencies = parsed.declarations.resolve(PLAYER, &code).unwrap();
+        assert_eq!(
+            dependencies.code,
+            r#"var Wc=["split","join"];function rot(a,b){return a.slice(b)+a.slice(0,b)};"#
+        );
+        assert!(dependencies.unresolved.is_empty());
+    }
+
+    #[test]
+    fn reports_unresolved_names() {
+        let parsed = parse_player(PLAYER).unwrap();
+        let dependencies = parsed
+            .declarations
+            .resolve(PLAYER, "function f(a){return Zz(a)+Math.PI+Wc[0]}")
+            .unwrap();
+        assert_eq!(dependencies.code, r#"var Wc=["split","join"];"#);
+        assert_eq!(dependencies.unresolved.into_iter().collect::<Vec<_>>(), ["Zz"]);
+    }
+
+    #[test]
+    fn exports_functions_at_the_end_of_their_scope() {
+        let parsed = parse_player(PLAYER).unwrap();
+        let code = parsed.declarations.export(PLAYER, &[("decrypt_nsig", "Xy")]).unwrap();
+        assert!(code.ends_with("\n;globalThis.decrypt_nsig=Xy;})(_yt_player);\n"));
+        assert!(parsed.declarations.export(PLAYER, &[("decrypt_nsig", "Qq")]).is_err());
+    }
+}
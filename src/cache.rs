@@ -0,0 +1,182 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use log::{info, warn};
+use serde::{Deserialize, Serialize};
+use tokio::fs;
+
+use crate::jobs::{next_revision, GlobalState, PlayerInfo};
+
+/// The part of a `PlayerInfo` that is kept on disk
+#[derive(Serialize, Deserialize)]
+pub struct CachedPlayer {
+    pub player_id: u32,
+    pub player_url: String,
+    pub discovery_source: String,
+    pub nsig_function_code: String,
+    pub sig_function_code: String,
+    pub sig_function_name: String,
+    pub signature_timestamp: u64,
+    /// UNIX time the player was fetched at
+    pub fetched_at: u64,
+}
+
+impl CachedPlayer {
+    pub fn from_player_info(player_info: &PlayerInfo) -> CachedPlayer {
+        CachedPlayer {
+            player_id: player_info.player_id,
+            player_url: player_info.player_url.clone(),
+            discovery_source: player_info.discovery_source.clone(),
+            nsig_function_code: player_info.nsig_function_code.clone(),
+            sig_function_code: player_info.sig_function_code.clone(),
+            sig_function_name: player_info.sig_function_name.clone(),
+            signature_timestamp: player_info.signature_timestamp,
+            fetched_at: player_info
+                .last_update
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+// variants can be paths, keep them from escaping the cache directory
+fn file_path(dir: &Path, variant: &str, extension: &str) -> PathBuf {
+    let name: String = variant
+        .chars()
+        .map(|x| match x.is_ascii_alphanumeric() || x == '-' || x == '_' {
+            true => x,
+            false => '_',
+        })
+        .collect();
+    dir.join(format!("{}.{}", name, extension))
+}
+
+async fn write_atomic(path: &Path, contents: &[u8]) -> std::io::Result<()> {
+    let temporary_path = path.with_extension("tmp");
+    fs::write(&temporary_path, contents).await?;
+    fs::rename(&temporary_path, path).await
+}
+
+/// Stores the player of `variant` and the player JS it was extracted from
+pub async fn save_player(
+    dir: &Path,
+    variant: &str,
+    player: &CachedPlayer,
+    player_javascript: &str,
+) -> std::io::Result<()> {
+    let serialized = toml::to_string(player).map_err(std::io::Error::other)?;
+    fs::create_dir_all(dir).await?;
+    write_atomic(&file_path(dir, variant, "js"), player_javascript.as_bytes()).await?;
+    write_atomic(&file_path(dir, variant, "toml"), serialized.as_bytes()).await
+}
+
+/// Loads the stored player of `variant` and its player JS
+pub async fn load_player(dir: &Path, variant: &str) -> std::io::Result<(CachedPlayer, String)> {
+    let serialized = fs::read_to_string(file_path(dir, variant, "toml")).await?;
+    let player: CachedPlayer = toml::from_str(&serialized)
+        .map_err(|x| std::io::Error::new(std::io::ErrorKind::InvalidData, x))?;
+    let player_javascript = fs::read_to_string(file_path(dir, variant, "js")).await?;
+    Ok((player, player_javascript))
+}
+
+/// Fills the players of all variants from the cache directory, returns true
+/// if the player of the default variant was loaded
+pub async fn load_players(state: &GlobalState) -> bool {
+    let dir = match &state.config.read().await.cache_dir {
+        Some(x) => PathBuf::from(x),
+        None => return false,
+    };
+
+    let players = state.players.read().await;
+    let mut has_default = false;
+    for (variant, player) in &players.variants {
+        let (cached, player_javascript) = match load_player(&dir, variant).await {
+            Ok(x) => x,
+            Err(x) if x.kind() == std::io::ErrorKind::NotFound => continue,
+            Err(x) => {
+                warn!("Could not load the cached player of variant {}: {}", variant, x);
+                continue;
+            }
+        };
+        info!(
+            "Loaded cached player {} of variant {}",
+            cached.player_url, variant
+        );
+
+        let mut player_info = player.lock().await;
+        player_info.player_id = cached.player_id;
+        player_info.player_url = cached.player_url;
+        player_info.discovery_source = cached.discovery_source;
+        player_info.player_js = Arc::new(player_javascript);
+        player_info.nsig_function_code = cached.nsig_function_code;
+        player_info.sig_function_code = cached.sig_function_code;
+        player_info.sig_function_name = cached.sig_function_name;
+        player_info.signature_timestamp = cached.signature_timestamp;
+        player_info.revision = next_revision();
+        player_info.has_player = 0xFF;
+        player_info.last_update = SystemTime::UNIX_EPOCH + Duration::from_secs(cached.fetched_at);
+
+        if *variant == players.default_variant {
+            has_default = true;
+        }
+    }
+    has_default
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_dir(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("inv_sig_helper_cache_{}_{}", std::process::id(), name))
+    }
+
+    fn cached_player() -> CachedPlayer {
+        CachedPlayer {
+            player_id: 0x1f8742dc,
+            player_url: "https://www.youtube.com/s/player/1f8742dc/base.js".to_string(),
+            discovery_source: "watch".to_string(),
+            nsig_function_code: "function decrypt_nsig(a){return a}".to_string(),
+            sig_function_code: "var sig=function(a){return a}".to_string(),
+            sig_function_name: "sig".to_string(),
+            signature_timestamp: 19876,
+            fetched_at: 1700000000,
+        }
+    }
+
+    #[test]
+    fn file_path_stays_in_directory() {
+        let dir = Path::new("/cache");
+        assert_eq!(file_path(dir, "tv-embedded_1", "js"), Path::new("/cache/tv-embedded_1.js"));
+        assert_eq!(file_path(dir, "../etc/passwd", "toml"), Path::new("/cache/___etc_passwd.toml"));
+        assert_eq!(file_path(dir, "", "js"), Path::new("/cache/.js"));
+    }
+
+    #[tokio::test]
+    async fn saved_player_loads_back() {
+        let dir = temp_dir("round_trip");
+        save_player(&dir, "tv/es6", &cached_player(), "var a=1;").await.unwrap();
+        let (loaded, player_javascript) = load_player(&dir, "tv/es6").await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+
+        let expected = cached_player();
+        assert_eq!(loaded.player_id, expected.player_id);
+        assert_eq!(loaded.player_url, expected.player_url);
+        assert_eq!(loaded.discovery_source, expected.discovery_source);
+        assert_eq!(loaded.nsig_function_code, expected.nsig_function_code);
+        assert_eq!(loaded.sig_function_code, expected.sig_function_code);
+        assert_eq!(loaded.sig_function_name, expected.sig_function_name);
+        assert_eq!(loaded.signature_timestamp, expected.signature_timestamp);
+        assert_eq!(loaded.fetched_at, expected.fetched_at);
+        assert_eq!(player_javascript, "var a=1;");
+    }
+
+    #[tokio::test]
+    async fn missing_player_is_not_found() {
+        let error = load_player(&temp_dir("missing"), "default").await.err().unwrap();
+        assert_eq!(error.kind(), std::io::ErrorKind::NotFound);
+    }
+}
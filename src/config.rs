@@ -0,0 +1,336 @@
+use serde::Deserialize;
+use std::{collections::BTreeMap, env, fs, path::Path, time::Duration};
+
+use crate::fixups::{FixupConfig, FixupRule};
+use crate::consts::{
+    DEFAULT_CONNECT_TIMEOUT, DEFAULT_COOKIES, DEFAULT_FETCH_RETRIES,
+    DEFAULT_FETCH_RETRY_DELAY_MS, DEFAULT_LOG_FILTER, DEFAULT_MAX_RESPONSE_SIZE,
+    DEFAULT_PLAYER_LOCALE, DEFAULT_PLAYER_VARIANT, DEFAULT_READ_TIMEOUT, DEFAULT_UPDATE_INTERVAL, DEFAULT_UPDATE_JITTER,
+    DEFAULT_UPDATE_RETRY_DELAY, DEFAULT_UPSTREAM_COOLDOWN, DEFAULT_USER_AGENT, PLAYER_VARIANTS,
+    TEST_YOUTUBE_EMBED, TEST_YOUTUBE_VIDEO,
+};
+
+#[derive(Debug)]
+pub enum ConfigError {
+    Io(std::io::Error),
+    Parse(toml::de::Error),
+    Invalid(String),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Io(x) => write!(f, "could not read configuration file: {}", x),
+            Self::Parse(x) => write!(f, "could not parse configuration file: {}", x),
+            Self::Invalid(x) => write!(f, "invalid configuration: {}", x),
+        }
+    }
+}
+
+/// A socket to accept clients on.
+///
+/// `address` is either `tcp:IP:PORT` or the path of a Unix socket.
+#[derive(Clone, Debug, Deserialize, PartialEq, Eq, Hash)]
+#[serde(deny_unknown_fields)]
+pub struct ListenerConfig {
+    pub address: String,
+    /// Permissions of the Unix socket, ignored for TCP listeners
+    pub permissions: Option<u32>,
+    /// Whether clients may pin and roll back the player
+    #[serde(default)]
+    pub admin: bool,
+}
+
+pub enum ListenerKind<'a> {
+    Tcp(&'a str),
+    Unix(&'a str),
+}
+
+impl ListenerConfig {
+    pub fn kind(&self) -> ListenerKind<'_> {
+        match self.address.strip_prefix("tcp:") {
+            Some(addr) => ListenerKind::Tcp(addr),
+            None => ListenerKind::Unix(self.address.strip_prefix("unix:").unwrap_or(&self.address)),
+        }
+    }
+}
+
+/// Where to look for the current player
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum DiscoveryStrategy {
+    /// The configured watch pages, `watch_urls`
+    Watch,
+    /// https://www.youtube.com/iframe_api
+    IframeApi,
+    /// The configured embed page, `embed_url`
+    Embed,
+    /// The pinned player id, `static_player_id`
+    Static,
+}
+
+impl std::fmt::Display for DiscoveryStrategy {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Watch => write!(f, "watch"),
+            Self::IframeApi => write!(f, "iframe_api"),
+            Self::Embed => write!(f, "embed"),
+            Self::Static => write!(f, "static"),
+        }
+    }
+}
+
+pub static DEFAULT_DISCOVERY_STRATEGIES: &[DiscoveryStrategy] = &[
+    DiscoveryStrategy::Watch,
+    DiscoveryStrategy::IframeApi,
+    DiscoveryStrategy::Embed,
+    DiscoveryStrategy::Static,
+];
+
+#[derive(Clone, Debug, Default, Deserialize, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// Log filter in `RUST_LOG` syntax, takes precedence over the environment
+    pub log_level: Option<String>,
+    /// Number of JavaScript runtimes, defaults to the available parallelism
+    pub js_runtimes: Option<usize>,
+    /// Sockets to listen on, replaces the socket given on the command line
+    pub listeners: Vec<ListenerConfig>,
+    /// Seconds between automatic player updates, 0 disables them
+    pub update_interval: Option<u64>,
+    /// Maximum random delay in seconds added to every automatic update
+    pub update_jitter: Option<u64>,
+    /// Seconds to wait before retrying a failed automatic update, doubled on
+    /// every consecutive failure up to `update_interval`
+    pub update_retry_delay: Option<u64>,
+    /// How many times a failed request to YouTube is retried
+    pub fetch_retries: Option<u32>,
+    /// Milliseconds before the first retry of a request to YouTube, doubled on every retry
+    pub fetch_retry_delay_ms: Option<u64>,
+    /// Minimum number of seconds between two player fetches from YouTube
+    pub upstream_cooldown: Option<u64>,
+    /// Proxies for the requests to YouTube (`http://`, `https://` or `socks5://`
+    /// URLs, optionally with credentials), the next one is used after a failure
+    pub proxies: Vec<String>,
+    /// Seconds to wait for a connection to YouTube
+    pub connect_timeout: Option<u64>,
+    /// Seconds to wait for data from YouTube before a request fails
+    pub read_timeout: Option<u64>,
+    pub user_agent: Option<String>,
+    /// Headers sent with every request to YouTube, in addition to the defaults
+    pub headers: BTreeMap<String, String>,
+    /// Cookies sent with every request to YouTube, replaces the default consent cookie
+    pub cookies: Option<BTreeMap<String, String>>,
+    /// Maximum size in bytes of a response from YouTube
+    pub max_response_size: Option<usize>,
+    /// Player JS variant, one of `PLAYER_VARIANTS` or a path below `/s/player/{id}/`
+    pub player_variant: Option<String>,
+    /// Variants served in addition to `player_variant`
+    pub player_variants: Vec<String>,
+    pub player_locale: Option<String>,
+    /// Order in which the player discovery strategies are tried
+    pub discovery_strategies: Option<Vec<DiscoveryStrategy>>,
+    pub watch_urls: Vec<String>,
+    pub embed_url: Option<String>,
+    /// Player id used by the `static` discovery strategy, skipped if unset
+    pub static_player_id: Option<String>,
+    /// Directory the last fetched players are kept in to be served right
+    /// after a restart, nothing is cached if unset
+    pub cache_dir: Option<String>,
+    /// File with extraction patterns replacing the compiled-in ones
+    pub patterns_file: Option<String>,
+    /// Rewrites of the extracted code, replacing built-in rules of the same
+    /// name or applied after them
+    pub fixups: Vec<FixupConfig>,
+    /// Find the nsig function by running the functions of the player JS when
+    /// neither the parser nor the patterns find it
+    pub nsig_search: bool,
+    /// Run the whole player JS in a stubbed browser environment when the
+    /// extracted functions cannot be found or fail the self-test
+    pub full_player_fallback: bool,
+}
+
+impl Config {
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Config, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let config: Config = toml::from_str(&contents).map_err(ConfigError::Parse)?;
+        for variant in config.player_variants() {
+            config.player_path(&variant)?;
+        }
+        for fixup in &config.fixups {
+            FixupRule::from_config(fixup)?;
+        }
+        Ok(config)
+    }
+
+    pub fn log_filter(&self) -> String {
+        match &self.log_level {
+            Some(x) => x.clone(),
+            None => env::var("RUST_LOG").unwrap_or_else(|_| DEFAULT_LOG_FILTER.to_string()),
+        }
+    }
+
+    pub fn update_interval(&self) -> Option<Duration> {
+        match self.update_interval.unwrap_or(DEFAULT_UPDATE_INTERVAL) {
+            0 => None,
+            x => Some(Duration::from_secs(x)),
+        }
+    }
+
+    pub fn update_jitter(&self) -> Duration {
+        Duration::from_secs(self.update_jitter.unwrap_or(DEFAULT_UPDATE_JITTER))
+    }
+
+    pub fn update_retry_delay(&self) -> Duration {
+        Duration::from_secs(self.update_retry_delay.unwrap_or(DEFAULT_UPDATE_RETRY_DELAY))
+    }
+
+    pub fn fetch_retries(&self) -> u32 {
+        self.fetch_retries.unwrap_or(DEFAULT_FETCH_RETRIES)
+    }
+
+    pub fn fetch_retry_delay(&self) -> Duration {
+        Duration::from_millis(self.fetch_retry_delay_ms.unwrap_or(DEFAULT_FETCH_RETRY_DELAY_MS))
+    }
+
+    pub fn upstream_cooldown(&self) -> Duration {
+        Duration::from_secs(self.upstream_cooldown.unwrap_or(DEFAULT_UPSTREAM_COOLDOWN))
+    }
+
+    pub fn connect_timeout(&self) -> Duration {
+        Duration::from_secs(self.connect_timeout.unwrap_or(DEFAULT_CONNECT_TIMEOUT))
+    }
+
+    pub fn read_timeout(&self) -> Duration {
+        Duration::from_secs(self.read_timeout.unwrap_or(DEFAULT_READ_TIMEOUT))
+    }
+
+    pub fn user_agent(&self) -> &str {
+        self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT)
+    }
+
+    pub fn cookies(&self) -> BTreeMap<String, String> {
+        match &self.cookies {
+            Some(x) => x.clone(),
+            None => DEFAULT_COOKIES
+                .iter()
+                .map(|(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+        }
+    }
+
+    pub fn max_response_size(&self) -> usize {
+        self.max_response_size.unwrap_or(DEFAULT_MAX_RESPONSE_SIZE)
+    }
+
+    /// All served player variants, starting with the default one
+    pub fn player_variants(&self) -> Vec<String> {
+        let mut variants = vec![self
+            .player_variant
+            .clone()
+            .unwrap_or_else(|| DEFAULT_PLAYER_VARIANT.to_string())];
+        for variant in &self.player_variants {
+            if !variants.contains(variant) {
+                variants.push(variant.clone());
+            }
+        }
+        variants
+    }
+
+    pub fn discovery_strategies(&self) -> Vec<DiscoveryStrategy> {
+        match &self.discovery_strategies {
+            Some(x) => x.clone(),
+            None => DEFAULT_DISCOVERY_STRATEGIES.to_vec(),
+        }
+    }
+
+    pub fn watch_urls(&self) -> Vec<String> {
+        match self.watch_urls.is_empty() {
+            true => vec![TEST_YOUTUBE_VIDEO.to_string()],
+            false => self.watch_urls.clone(),
+        }
+    }
+
+    pub fn embed_url(&self) -> &str {
+        self.embed_url.as_deref().unwrap_or(TEST_YOUTUBE_EMBED)
+    }
+
+    /// Whether `variant` is downloaded from the player URL found on the page
+    /// instead of a URL built from the configured variant and locale
+    pub fn uses_discovered_url(&self, variant: &str) -> bool {
+        self.player_variant.is_none()
+            && self.player_locale.is_none()
+            && variant == DEFAULT_PLAYER_VARIANT
+    }
+
+    /// Path of the player JS of `variant` below `/s/player/{id}/`
+    pub fn player_path(&self, variant: &str) -> Result<String, ConfigError> {
+        let template = match PLAYER_VARIANTS.iter().find(|(name, _)| *name == variant) {
+            Some((_, template)) => template,
+            None if variant.ends_with(".js") => variant,
+            None => {
+                return Err(ConfigError::Invalid(format!(
+                    "unknown player variant '{}'",
+                    variant
+                )))
+            }
+        };
+        let locale = self.player_locale.as_deref().unwrap_or(DEFAULT_PLAYER_LOCALE);
+        Ok(template.replace("{locale}", locale))
+    }
+
+    /// Whether the settings of the HTTP client for YouTube differ from `other`
+    pub fn upstream_changed(&self, other: &Config) -> bool {
+        self.proxies != other.proxies
+            || self.connect_timeout != other.connect_timeout
+            || self.read_timeout != other.read_timeout
+            || self.user_agent != other.user_agent
+            || self.headers != other.headers
+            || self.cookies != other.cookies
+            || self.max_response_size != other.max_response_size
+    }
+
+    /// Whether the settings the functions are extracted with differ from `other`
+    pub fn patterns_changed(&self, other: &Config) -> bool {
+        self.patterns_file != other.patterns_file || self.fixups != other.fixups
+    }
+
+    /// Names of the settings that differ from `other` but only take effect after a restart
+    pub fn restart_required(&self, other: &Config) -> Vec<&'static str> {
+        let mut changed = Vec::new();
+        if self.js_runtimes != other.js_runtimes {
+            changed.push("js_runtimes");
+        }
+        changed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_changed_settings() {
+        let config = Config::default();
+        let log_level = Config {
+            log_level: Some("debug".to_string()),
+            ..Default::default()
+        };
+        assert!(!log_level.upstream_changed(&config));
+        assert!(!log_level.patterns_changed(&config));
+
+        let proxies = Config {
+            proxies: vec!["socks5://127.0.0.1:1080".to_string()],
+            ..Default::default()
+        };
+        assert!(proxies.upstream_changed(&config));
+        assert!(!proxies.patterns_changed(&config));
+
+        let patterns_file = Config {
+            patterns_file: Some("patterns.toml".to_string()),
+            ..Default::default()
+        };
+        assert!(patterns_file.patterns_changed(&config));
+        assert!(!patterns_file.upstream_changed(&config));
+    }
+}
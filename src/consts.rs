@@ -1,13 +1,53 @@
 use lazy_regex::{regex, Lazy};
 use regex::Regex;
+use std::{ops::RangeInclusive, time::Duration};
 
 pub static DEFAULT_SOCK_PATH: &str = "/tmp/inv_sig_helper.sock";
 pub static DEFAULT_SOCK_PERMS: u32 = 0o755;
 pub static DEFAULT_TCP_URL: &str = "127.0.0.1:12999";
+pub static DEFAULT_LOG_FILTER: &str = "info";
+pub static DEFAULT_UPDATE_INTERVAL: u64 = 3600;
+pub static DEFAULT_UPDATE_JITTER: u64 = 300;
+pub static DEFAULT_UPDATE_RETRY_DELAY: u64 = 60;
+pub static DEFAULT_FETCH_RETRIES: u32 = 3;
+pub static DEFAULT_FETCH_RETRY_DELAY_MS: u64 = 1000;
+pub static DEFAULT_UPSTREAM_COOLDOWN: u64 = 60;
+pub static DEFAULT_CONNECT_TIMEOUT: u64 = 10;
+pub static DEFAULT_READ_TIMEOUT: u64 = 30;
+pub static DEFAULT_MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+pub static DEFAULT_USER_AGENT: &str =
+    "Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:128.0) Gecko/20100101 Firefox/128.0";
+pub static DEFAULT_ACCEPT_LANGUAGE: &str = "en-US,en;q=0.9";
+// skips the EU consent page
+pub static DEFAULT_COOKIES: &[(&str, &str)] = &[("SOCS", "CAI")];
+pub static YOUTUBE_URL: &str = "https://www.youtube.com";
+pub static MAX_CACHED_RESPONSES: usize = 16;
 
 pub static TEST_YOUTUBE_VIDEO: &str = "https://www.youtube.com/watch?v=jNQXAC9IVRw";
+pub static TEST_YOUTUBE_EMBED: &str = "https://www.youtube.com/embed/jNQXAC9IVRw";
+pub static YOUTUBE_IFRAME_API: &str = "https://www.youtube.com/iframe_api";
 
-pub static REGEX_PLAYER_ID: &Lazy<Regex> = regex!("\\/s\\/player\\/([0-9a-f]{8})");
+pub static DEFAULT_PLAYER_VARIANT: &str = "player_ias";
+pub static DEFAULT_PLAYER_LOCALE: &str = "en_US";
+pub static DEFAULT_PLAYER_PATH: &str = "player_ias.vflset/en_US/base.js";
+// path of the player JS below /s/player/{id}/, {locale} is replaced by the locale
+pub static PLAYER_VARIANTS: &[(&str, &str)] = &[
+    ("player_ias", "player_ias.vflset/{locale}/base.js"),
+    ("player_ias_tcc", "player_ias_tcc.vflset/{locale}/base.js"),
+    ("player_ias_tce", "player_ias_tce.vflset/{locale}/base.js"),
+    ("player_es5", "player_es5.vflset/{locale}/base.js"),
+    ("player_es6", "player_es6.vflset/{locale}/base.js"),
+    ("tv-player-ias", "tv-player-ias.vflset/tv-player-ias.js"),
+    ("tv-player-es6", "tv-player-es6.vflset/tv-player-es6.js"),
+    ("player-plasma-ias-phone", "player-plasma-ias-phone-{locale}.vflset/base.js"),
+    ("player-plasma-ias-tablet", "player-plasma-ias-tablet-{locale}.vflset/base.js"),
+];
+
+pub static REGEX_PLAYER_ID: &Lazy<Regex> = regex!("\\/s\\/player\\/([0-9a-zA-Z_-]+)\\/");
+pub static REGEX_PLAYER_JS_URL: &Lazy<Regex> =
+    regex!(r#""(?:jsUrl|PLAYER_JS_URL)"\s*:\s*"([^"]+)""#);
+// splits a player URL into the part up to the player id directory, the id and the path after it
+pub static REGEX_PLAYER_URL_PARTS: &Lazy<Regex> = regex!(r"^(.*/s/player/([^/]+)/)(.+)$");
 pub static NSIG_FUNCTION_ARRAYS: &[&str] = &[
     r#"null\)&&\([a-zA-Z]=(?P<nfunc>[_a-zA-Z0-9$]+)\[(?P<idx>\d+)\]\([a-zA-Z0-9]\)"#,
     r#"(?x)&&\(b="n+"\[[a-zA-Z0-9.+$]+\],c=a\.get\(b\)\)&&\(c=(?P<nfunc>[a-zA-Z0-9$]+)(?:\[(?P<idx>\d+)\])?\([a-zA-Z0-9]\)"#,
@@ -32,4 +72,51 @@ pub static REGEX_SIGNATURE_FUNCTION_PATTERNS: &[&str] = &[
 pub static REGEX_HELPER_OBJ_NAME: &Lazy<Regex> = regex!(";([A-Za-z0-9_\\$]{2,})(?:\\.|\\[)");
 
 pub static NSIG_FUNCTION_NAME: &str = "decrypt_nsig";
-pub static SIG_FUNCTION_NAME: &str = "decrypt_sig";
+/// Name the sig function is exported as when the whole player is run
+pub static FULL_PLAYER_SIG_FUNCTION_NAME: &str = "decrypt_sig";
+
+/// Minimal browser environment evaluated before the whole player JS
+pub static FULL_PLAYER_PRELUDE: &str = r#"var window=globalThis,self=globalThis,top=globalThis,parent=globalThis;
+(function(g){
+var noop=function(){};
+var element=function(){return {style:{},setAttribute:noop,getAttribute:function(){return null},appendChild:noop,removeChild:noop,addEventListener:noop,removeEventListener:noop}};
+g.location={href:"https://www.youtube.com/watch?v=jNQXAC9IVRw",protocol:"https:",host:"www.youtube.com",hostname:"www.youtube.com",origin:"https://www.youtube.com",port:"",pathname:"/watch",search:"?v=jNQXAC9IVRw",hash:""};
+g.navigator={userAgent:"Mozilla/5.0 (Windows NT 10.0; Win64; x64; rv:128.0) Gecko/20100101 Firefox/128.0",language:"en-US",languages:["en-US","en"],platform:"Win32",vendor:"",cookieEnabled:true,onLine:true};
+g.document={location:g.location,cookie:"",referrer:"",readyState:"complete",documentElement:element(),head:element(),body:element(),createElement:element,getElementById:function(){return null},getElementsByTagName:function(){return []},querySelector:function(){return null},querySelectorAll:function(){return []},addEventListener:noop,removeEventListener:noop};
+g.addEventListener=noop;
+g.removeEventListener=noop;
+g.setTimeout=function(){return 0};
+g.clearTimeout=noop;
+g.setInterval=function(){return 0};
+g.clearInterval=noop;
+})(globalThis);
+"#;
+
+/// Globals of the JavaScript runtime, names the extracted code may use
+/// without a declaration in the player
+pub static JS_GLOBALS: &[&str] = &[
+    "Array", "ArrayBuffer", "BigInt", "Boolean", "DataView", "Date", "Error", "EvalError",
+    "Float32Array", "Float64Array", "Function", "Infinity", "Int16Array", "Int32Array",
+    "Int8Array", "JSON", "Map", "Math", "NaN", "Number", "Object", "Promise", "Proxy",
+    "RangeError", "ReferenceError", "Reflect", "RegExp", "Set", "String", "Symbol",
+    "SyntaxError", "TypeError", "URIError", "Uint16Array", "Uint32Array", "Uint8Array",
+    "Uint8ClampedArray", "WeakMap", "WeakSet", "arguments", "decodeURI",
+    "decodeURIComponent", "encodeURI", "encodeURIComponent", "escape", "eval", "globalThis",
+    "isFinite", "isNaN", "parseFloat", "parseInt", "undefined", "unescape",
+];
+/// Maximum number of player declarations pulled in for the extracted code
+pub static MAX_DEPENDENCIES: usize = 200;
+
+// samples for the self-test of a new player
+pub static SELF_TEST_NSIG_INPUT: &str = "iHywZkMipkszqVBhe";
+pub static SELF_TEST_SIG_INPUT: &str = "AOq0QJ8wRQIhAM8k2ACBbjLq3Wt8fMtTHf0hUZ6nPrnQf7YzDd4Yv7zKAiBvRG4oMLD3cI7qXRkVRSdsQnvq1Nr-5uS4HcxPvwWWn6A==";
+// largest function code accepted by OVERRIDE_PLAYER
+pub static MAX_OVERRIDE_CODE_SIZE: usize = 1024 * 1024;
+pub static SELF_TEST_TIME_LIMIT: Duration = Duration::from_secs(5);
+
+// limits of the search for the nsig function by running the player functions
+pub static NSIG_CANDIDATE_SIZES: RangeInclusive<usize> = 32..=100_000;
+pub static MAX_NSIG_CANDIDATES: usize = 1000;
+pub static NSIG_CANDIDATE_TIME_LIMIT: Duration = Duration::from_millis(50);
+pub static NSIG_CANDIDATE_MEMORY_LIMIT: usize = 64 * 1024 * 1024;
+pub static NSIG_SEARCH_TIME_LIMIT: Duration = Duration::from_secs(30);
@@ -0,0 +1,341 @@
+use log::{debug, info, warn};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::config::ConfigError;
+
+/// Which extracted code a fixup rule is applied to
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FixupTarget {
+    Nsig,
+    Sig,
+    #[default]
+    Both,
+}
+
+impl std::fmt::Display for FixupTarget {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Nsig => write!(f, "nsig"),
+            Self::Sig => write!(f, "sig"),
+            Self::Both => write!(f, "nsig and sig"),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum FixupKind {
+    Replace,
+    PrependGlobal,
+    WrapFunction,
+}
+
+/// A fixup rule as written in the `fixups` list of the configuration
+#[derive(Clone, Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FixupConfig {
+    /// Rules named like a built-in rule replace it
+    pub name: String,
+    pub kind: FixupKind,
+    #[serde(default)]
+    pub applies_to: FixupTarget,
+    /// `replace`: regex to find, `GLOBAL_VAR_NAME` is replaced by the name of
+    /// the global array of the player
+    pub find: Option<String>,
+    /// `replace`: replacement, may refer to groups of `find` as `$1` or `${name}`
+    pub replace: Option<String>,
+    /// `wrap_function`: code inserted at the start of the function body
+    pub before: Option<String>,
+    /// `wrap_function`: code inserted at the end of the function body
+    pub after: Option<String>,
+}
+
+enum FixupAction {
+    Replace { find: String, replace: String },
+    /// Puts the declaration of the global array of the player in front of the code
+    PrependGlobal,
+    WrapFunction { before: String, after: String },
+}
+
+pub struct FixupRule {
+    name: String,
+    target: FixupTarget,
+    action: FixupAction,
+}
+
+/// The global array variable of a player JS, its declaration and its name
+pub struct GlobalVar {
+    pub code: String,
+    pub name: String,
+}
+
+fn find_regex(find: &str, global_var_name: &str) -> Result<Regex, regex::Error> {
+    Regex::new(&find.replace("GLOBAL_VAR_NAME", &regex::escape(global_var_name)))
+}
+
+impl FixupRule {
+    pub fn from_config(config: &FixupConfig) -> Result<FixupRule, ConfigError> {
+        let action = match config.kind {
+            FixupKind::Replace => {
+                let find = match &config.find {
+                    Some(x) => x.clone(),
+                    None => {
+                        return Err(ConfigError::Invalid(format!(
+                            "fixup '{}' has no 'find' pattern",
+                            config.name
+                        )))
+                    }
+                };
+                find_regex(&find, "name").map_err(|x| {
+                    ConfigError::Invalid(format!("fixup '{}' pattern: {}", config.name, x))
+                })?;
+                FixupAction::Replace {
+                    find,
+                    replace: config.replace.clone().unwrap_or_default(),
+                }
+            }
+            FixupKind::PrependGlobal => FixupAction::PrependGlobal,
+            FixupKind::WrapFunction => {
+                if config.before.is_none() && config.after.is_none() {
+                    return Err(ConfigError::Invalid(format!(
+                        "fixup '{}' has neither 'before' nor 'after'",
+                        config.name
+                    )));
+                }
+                FixupAction::WrapFunction {
+                    before: config.before.clone().unwrap_or_default(),
+                    after: config.after.clone().unwrap_or_default(),
+                }
+            }
+        };
+        Ok(FixupRule {
+            name: config.name.clone(),
+            target: config.applies_to,
+            action,
+        })
+    }
+
+    /// Returns the fixed code, `None` if the rule does not apply to `code`
+    fn apply(&self, code: &str, function_name: &str, global_var: Option<&GlobalVar>) -> Option<String> {
+        match &self.action {
+            FixupAction::Replace { find, replace } => {
+                let global_var_name = global_var.map(|x| x.name.as_str()).unwrap_or_default();
+                let regex = match find_regex(find, global_var_name) {
+                    Ok(x) => x,
+                    Err(x) => {
+                        warn!("Pattern of fixup {} is invalid: {}", self.name, x);
+                        return None;
+                    }
+                };
+                match regex.is_match(code) {
+                    true => Some(regex.replace_all(code, replace.as_str()).to_string()),
+                    false => None,
+                }
+            }
+            FixupAction::PrependGlobal => {
+                let global_var = global_var?;
+                Some(format!("{};{}", global_var.code, code))
+            }
+            FixupAction::WrapFunction { before, after } => {
+                let (start, end) = function_body(code, function_name)?;
+                let mut result = String::with_capacity(code.len() + before.len() + after.len());
+                result += &code[..start];
+                result += before;
+                result += &code[start..end];
+                result += after;
+                result += &code[end..];
+                Some(result)
+            }
+        }
+    }
+}
+
+/// Range of the body of `function_name`, which is the last function of the
+/// extracted code
+fn function_body(code: &str, function_name: &str) -> Option<(usize, usize)> {
+    let declaration = code
+        .find(&format!("function {}(", function_name))
+        .or_else(|| code.find(&format!("{}=function(", function_name)))?;
+    let start = declaration + code[declaration..].find('{')? + 1;
+    let end = code.rfind('}')?;
+    match start <= end {
+        true => Some((start, end)),
+        false => None,
+    }
+}
+
+/// The compiled-in rules, applied in this order
+pub fn builtin_rules() -> Vec<FixupRule> {
+    vec![
+        FixupRule {
+            name: "prepend-global-array".to_string(),
+            target: FixupTarget::Both,
+            action: FixupAction::PrependGlobal,
+        },
+        FixupRule {
+            // anti-tampering check returning early when run outside the player
+            name: "strip-undefined-check".to_string(),
+            target: FixupTarget::Both,
+            action: FixupAction::Replace {
+                find: r#";\s*if\s*\(\s*typeof\s+[a-zA-Z0-9_$]+\s*===?\s*(?:"undefined"|'undefined'|GLOBAL_VAR_NAME\[\d+\])\s*\)\s*return\s+\w+;"#.to_string(),
+                replace: ";".to_string(),
+            },
+        },
+    ]
+}
+
+/// The built-in rules with the configured ones replacing them by name or appended
+pub fn fixup_rules(configured: &[FixupConfig]) -> Result<Vec<FixupRule>, ConfigError> {
+    let mut rules = builtin_rules();
+    for config in configured {
+        let rule = FixupRule::from_config(config)?;
+        match rules.iter_mut().find(|x| x.name == rule.name) {
+            Some(x) => *x = rule,
+            None => rules.push(rule),
+        }
+    }
+    Ok(rules)
+}
+
+/// Applies the rules for `target` to the code defining `function_name` in
+/// order, returns the fixed code and the names of the rules that applied
+pub fn fixed_code<'r>(
+    rules: &'r [FixupRule],
+    target: FixupTarget,
+    function_name: &str,
+    code: String,
+    global_var: Option<&GlobalVar>,
+) -> (String, Vec<&'r str>) {
+    let mut code = code;
+    let mut applied = Vec::new();
+    for rule in rules {
+        if rule.target != target && rule.target != FixupTarget::Both {
+            continue;
+        }
+        if let Some(x) = rule.apply(&code, function_name, global_var) {
+            code = x;
+            applied.push(rule.name.as_str());
+        }
+    }
+    (code, applied)
+}
+
+/// Applies the rules for `target` to the code defining `function_name` in order
+pub fn apply_fixups(
+    rules: &[FixupRule],
+    target: FixupTarget,
+    function_name: &str,
+    code: String,
+    global_var: Option<&GlobalVar>,
+) -> String {
+    let (code, applied) = fixed_code(rules, target, function_name, code, global_var);
+    for name in &applied {
+        info!("Applied fixup {} to the {} code", name, target);
+    }
+    if applied.is_empty() {
+        debug!("No fixup applied to the {} code", target);
+    }
+    code
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule(kind: FixupKind) -> FixupConfig {
+        FixupConfig {
+            name: "test".to_string(),
+            kind,
+            applies_to: FixupTarget::Both,
+            find: None,
+            replace: None,
+            before: None,
+            after: None,
+        }
+    }
+
+    fn global_var() -> GlobalVar {
+        GlobalVar {
+            code: r#"var Zx="a b".split(" ")"#.to_string(),
+            name: "Zx".to_string(),
+        }
+    }
+
+    #[test]
+    fn builtin_rules_prepend_global_and_strip_check() {
+        let code = r#"function decrypt_nsig(a){var b=a;if(typeof Qr==="undefined")return a;return b+Zx[0]}"#;
+        let rules = builtin_rules();
+        let (code, applied) = fixed_code(
+            &rules,
+            FixupTarget::Nsig,
+            "decrypt_nsig",
+            code.to_string(),
+            Some(&global_var()),
+        );
+        assert_eq!(
+            code,
+            r#"var Zx="a b".split(" ");function decrypt_nsig(a){var b=a;return b+Zx[0]}"#
+        );
+        assert_eq!(applied, ["prepend-global-array", "strip-undefined-check"]);
+    }
+
+    #[test]
+    fn replace_uses_global_var_name() {
+        let mut config = rule(FixupKind::Replace);
+        config.find = Some(r"GLOBAL_VAR_NAME\[(\d)\]".to_string());
+        config.replace = Some("g$1".to_string());
+        let rule = FixupRule::from_config(&config).unwrap();
+        assert_eq!(
+            rule.apply("return Zx[0]+Zx[1]", "f", Some(&global_var())).as_deref(),
+            Some("return g0+g1")
+        );
+        assert_eq!(rule.apply("return 1", "f", Some(&global_var())), None);
+    }
+
+    #[test]
+    fn wrap_function_inserts_around_body() {
+        let mut config = rule(FixupKind::WrapFunction);
+        config.before = Some("try{".to_string());
+        config.after = Some("}catch(e){return a}".to_string());
+        let rule = FixupRule::from_config(&config).unwrap();
+        assert_eq!(
+            rule.apply("var c=1;f=function(a){return a+c}", "f", None).as_deref(),
+            Some("var c=1;f=function(a){try{return a+c}catch(e){return a}}")
+        );
+        assert_eq!(rule.apply("var c=1;", "f", None), None);
+    }
+
+    #[test]
+    fn rules_apply_to_their_target_only() {
+        let mut config = rule(FixupKind::Replace);
+        config.applies_to = FixupTarget::Sig;
+        config.find = Some("x".to_string());
+        config.replace = Some("y".to_string());
+        let rules = fixup_rules(&[config]).unwrap();
+        let (code, _) = fixed_code(&rules, FixupTarget::Nsig, "f", "x".to_string(), None);
+        assert_eq!(code, "x");
+        let (code, _) = fixed_code(&rules, FixupTarget::Sig, "f", "x".to_string(), None);
+        assert_eq!(code, "y");
+    }
+
+    #[test]
+    fn configured_rule_replaces_builtin_by_name() {
+        let mut config = rule(FixupKind::Replace);
+        config.name = "strip-undefined-check".to_string();
+        config.find = Some("a".to_string());
+        let rules = fixup_rules(&[config]).unwrap();
+        assert_eq!(rules.len(), builtin_rules().len());
+        assert!(matches!(&rules[1].action, FixupAction::Replace { find, .. } if find == "a"));
+    }
+
+    #[test]
+    fn invalid_rules_are_rejected() {
+        assert!(FixupRule::from_config(&rule(FixupKind::Replace)).is_err());
+        assert!(FixupRule::from_config(&rule(FixupKind::WrapFunction)).is_err());
+        let mut config = rule(FixupKind::Replace);
+        config.find = Some("(".to_string());
+        assert!(matches!(FixupRule::from_config(&config), Err(ConfigError::Invalid(_))));
+    }
+}
@@ -1,11 +1,42 @@
 use futures::SinkExt;
-use rquickjs::{async_with, AsyncContext, AsyncRuntime};
-use std::{num::NonZeroUsize, sync::Arc, thread::available_parallelism, time::SystemTime};
-use log::{debug, error};
-use tokio::{runtime::Handle, sync::Mutex, task::block_in_place};
+use rquickjs::{async_with, context::EvalOptions, AsyncContext, AsyncRuntime, Ctx, FromJs};
+use std::{
+    collections::{BTreeMap, HashMap},
+    num::NonZeroUsize,
+    path::PathBuf,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    thread::available_parallelism,
+    time::{Instant, SystemTime},
+};
+use log::{debug, error, info, warn};
+use tokio::sync::{Mutex, Notify, RwLock};
 use tub::Pool;
 
-use crate::{consts::NSIG_FUNCTION_NAME, opcode::OpcodeResponse, player::fetch_update};
+use crate::{
+    config::Config,
+    consts::NSIG_FUNCTION_NAME,
+    opcode::{Opcode, OpcodeResponse},
+    patterns::Patterns,
+    player::{
+        clear_override, dry_run_update, fetch_update, override_player, pin_player,
+        reload_patterns, roll_back_player, ExtractedPlayer, FetchUpdateStatus, SharedUpdate,
+    },
+    upstream::UpstreamClient,
+};
+
+/// Evaluates code taken from the player JS. `Ctx::eval` forces strict mode,
+/// but the player is sloppy code: its wrapper takes `window` from `this`.
+pub fn eval_player_code<'js, V: FromJs<'js>, S: Into<Vec<u8>>>(
+    ctx: &Ctx<'js>,
+    code: S,
+) -> rquickjs::Result<V> {
+    let mut options = EvalOptions::default();
+    options.strict = false;
+    ctx.eval_with_options(code, options)
+}
 
 pub enum JobOpcode {
     ForceUpdate,
@@ -14,6 +45,17 @@ pub enum JobOpcode {
     GetSignatureTimestamp,
     PlayerStatus,
     PlayerUpdateTimestamp,
+    DecryptNSignatureVariant,
+    DecryptSignatureVariant,
+    GetSignatureTimestampVariant,
+    PinPlayer,
+    UnpinPlayer,
+    RollbackPlayer,
+    DryRunUpdate,
+    OverridePlayer,
+    ClearOverride,
+    ReloadPatterns,
+    PlayerDetails,
     UnknownOpcode,
 }
 
@@ -26,6 +68,17 @@ impl std::fmt::Display for JobOpcode {
             Self::GetSignatureTimestamp => write!(f, "GetSignatureTimestamp"),
             Self::PlayerStatus => write!(f, "PlayerStatus"),
             Self::PlayerUpdateTimestamp => write!(f, "PlayerUpdateTimestamp"),
+            Self::DecryptNSignatureVariant => write!(f, "DecryptNSignatureVariant"),
+            Self::DecryptSignatureVariant => write!(f, "DecryptSignatureVariant"),
+            Self::GetSignatureTimestampVariant => write!(f, "GetSignatureTimestampVariant"),
+            Self::PinPlayer => write!(f, "PinPlayer"),
+            Self::UnpinPlayer => write!(f, "UnpinPlayer"),
+            Self::RollbackPlayer => write!(f, "RollbackPlayer"),
+            Self::DryRunUpdate => write!(f, "DryRunUpdate"),
+            Self::OverridePlayer => write!(f, "OverridePlayer"),
+            Self::ClearOverride => write!(f, "ClearOverride"),
+            Self::ReloadPatterns => write!(f, "ReloadPatterns"),
+            Self::PlayerDetails => write!(f, "PlayerDetails"),
             Self::UnknownOpcode => write!(f, "UnknownOpcode"),
         }
     }
@@ -39,63 +92,250 @@ impl From<u8> for JobOpcode {
             0x03 => Self::GetSignatureTimestamp,
             0x04 => Self::PlayerStatus,
             0x05 => Self::PlayerUpdateTimestamp,
+            0x06 => Self::DecryptNSignatureVariant,
+            0x07 => Self::DecryptSignatureVariant,
+            0x08 => Self::GetSignatureTimestampVariant,
+            0x09 => Self::PinPlayer,
+            0x0A => Self::UnpinPlayer,
+            0x0B => Self::RollbackPlayer,
+            0x0C => Self::DryRunUpdate,
+            0x0D => Self::OverridePlayer,
+            0x0E => Self::ClearOverride,
+            0x0F => Self::ReloadPatterns,
+            0x10 => Self::PlayerDetails,
             _ => Self::UnknownOpcode,
         }
     }
 }
 
+#[derive(Clone)]
 pub struct PlayerInfo {
     pub nsig_function_code: String,
     pub sig_function_code: String,
     pub sig_function_name: String,
     pub signature_timestamp: u64,
     pub player_id: u32,
+    /// URL the player JS was downloaded from
+    pub player_url: String,
+    /// The discovery strategy that found the player
+    pub discovery_source: String,
+    /// The player JS itself, kept to extract the functions again
+    pub player_js: Arc<String>,
+    /// Changed with `next_revision` whenever the function code changes
+    pub revision: u64,
     pub has_player: u8,
     pub last_update: SystemTime,
+    /// The player that was active before this one
+    pub previous: Option<Box<PlayerInfo>>,
+    /// The extracted player, while its functions are replaced by a manual override
+    pub overridden: Option<Box<PlayerInfo>>,
 }
 
-pub struct JavascriptInterpreter {
-    js_runtime: AsyncRuntime,
+/// Functions supplied with `OVERRIDE_PLAYER`, empty fields keep the current value
+pub struct FunctionOverride {
+    pub nsig_function_code: String,
+    pub sig_function_name: String,
+    pub sig_function_code: String,
+    pub signature_timestamp: u64,
+}
+
+static REVISIONS: AtomicU64 = AtomicU64::new(1);
+
+/// A revision no player has had yet. Revisions are shared by all variants, so
+/// a variant removed and configured again cannot reuse the revision of the
+/// code its interpreter contexts still have.
+pub fn next_revision() -> u64 {
+    REVISIONS.fetch_add(1, Ordering::Relaxed)
+}
+
+impl Default for PlayerInfo {
+    fn default() -> Self {
+        PlayerInfo {
+            nsig_function_code: Default::default(),
+            sig_function_code: Default::default(),
+            sig_function_name: Default::default(),
+            player_id: Default::default(),
+            player_url: Default::default(),
+            discovery_source: Default::default(),
+            player_js: Default::default(),
+            revision: 0,
+            signature_timestamp: Default::default(),
+            has_player: 0x00,
+            last_update: SystemTime::now(),
+            previous: None,
+            overridden: None,
+        }
+    }
+}
+
+impl PlayerInfo {
+    /// Keeps a copy of the active player to roll back to
+    pub fn keep_as_previous(&mut self) {
+        if self.has_player == 0x00 {
+            return;
+        }
+        let mut previous = self.clone();
+        previous.previous = None;
+        self.previous = Some(Box::new(previous));
+        // a new player ends the override
+        self.overridden = None;
+    }
+
+    /// Activates newly extracted functions. While an override is active they
+    /// are kept for when it is cleared.
+    pub fn set_functions(&mut self, extracted: ExtractedPlayer) {
+        if let Some(overridden) = &mut self.overridden {
+            overridden.nsig_function_code = extracted.nsig_function_code;
+            overridden.sig_function_code = extracted.sig_function_code;
+            overridden.sig_function_name = extracted.sig_function_name;
+            overridden.signature_timestamp = extracted.signature_timestamp;
+            overridden.has_player = 0xFF;
+            return;
+        }
+        self.nsig_function_code = extracted.nsig_function_code;
+        self.sig_function_code = extracted.sig_function_code;
+        self.sig_function_name = extracted.sig_function_name;
+        self.signature_timestamp = extracted.signature_timestamp;
+        self.has_player = 0xFF;
+        self.revision = next_revision();
+    }
+
+    /// Replaces the functions with `function_override`, keeping the extracted
+    /// ones to go back to
+    pub fn apply_override(&mut self, function_override: FunctionOverride) {
+        if self.overridden.is_none() {
+            let mut extracted = self.clone();
+            extracted.previous = None;
+            self.overridden = Some(Box::new(extracted));
+        }
+        if !function_override.nsig_function_code.is_empty() {
+            self.nsig_function_code = function_override.nsig_function_code;
+        }
+        if !function_override.sig_function_code.is_empty() {
+            self.sig_function_name = function_override.sig_function_name;
+            self.sig_function_code = function_override.sig_function_code;
+        }
+        if function_override.signature_timestamp != 0 {
+            self.signature_timestamp = function_override.signature_timestamp;
+        }
+        self.revision = next_revision();
+        self.has_player = 0xFF;
+    }
+
+    /// Goes back to the extracted functions, returns false if there is no override
+    pub fn clear_override(&mut self) -> bool {
+        let extracted = match self.overridden.take() {
+            Some(x) => x,
+            None => return false,
+        };
+        self.nsig_function_code = extracted.nsig_function_code;
+        self.sig_function_name = extracted.sig_function_name;
+        self.sig_function_code = extracted.sig_function_code;
+        self.signature_timestamp = extracted.signature_timestamp;
+        self.has_player = extracted.has_player;
+        self.revision = next_revision();
+        true
+    }
+
+    /// Swaps the active and the previous player, returns false if there is
+    /// no previous player
+    pub fn roll_back(&mut self) -> bool {
+        let mut previous = match self.previous.take() {
+            Some(x) => *x,
+            None => return false,
+        };
+        // the interpreters still have the code of the current revision
+        previous.revision = next_revision();
+        let current = std::mem::replace(self, previous);
+        self.previous = Some(Box::new(current));
+        true
+    }
+}
+
+/// The JavaScript contexts of one player variant
+pub struct VariantContexts {
     sig_context: AsyncContext,
     nsig_context: AsyncContext,
-    sig_player_id: Mutex<u32>,
-    nsig_player_id: Mutex<u32>,
+    sig_revision: Mutex<u64>,
+    nsig_revision: Mutex<u64>,
+}
+
+pub struct JavascriptInterpreter {
+    js_runtime: AsyncRuntime,
+    contexts: Mutex<HashMap<String, Arc<VariantContexts>>>,
 }
 
 impl JavascriptInterpreter {
     pub fn new() -> JavascriptInterpreter {
-        let js_runtime = AsyncRuntime::new().unwrap();
-        // not ideal, but this is only done at startup
-        let nsig_context = block_in_place(|| {
-            Handle::current()
-                .block_on(AsyncContext::full(&js_runtime))
-                .unwrap()
-        });
-        let sig_context = block_in_place(|| {
-            Handle::current()
-                .block_on(AsyncContext::full(&js_runtime))
-                .unwrap()
-        });
         JavascriptInterpreter {
-            js_runtime,
-            sig_context,
-            nsig_context,
-            sig_player_id: Mutex::new(0),
-            nsig_player_id: Mutex::new(0),
+            js_runtime: AsyncRuntime::new().unwrap(),
+            contexts: Mutex::new(HashMap::new()),
         }
     }
+
+    /// Returns the contexts for `variant`, creating them on first use. The
+    /// contexts of variants no longer in `players` are dropped.
+    pub async fn contexts(&self, variant: &str, players: &Players) -> Arc<VariantContexts> {
+        let mut contexts = self.contexts.lock().await;
+        contexts.retain(|name, _| players.variants.contains_key(name));
+        if let Some(x) = contexts.get(variant) {
+            return x.clone();
+        }
+        let variant_contexts = Arc::new(VariantContexts {
+            sig_context: AsyncContext::full(&self.js_runtime).await.unwrap(),
+            nsig_context: AsyncContext::full(&self.js_runtime).await.unwrap(),
+            sig_revision: Mutex::new(0),
+            nsig_revision: Mutex::new(0),
+        });
+        contexts.insert(variant.to_string(), variant_contexts.clone());
+        variant_contexts
+    }
+}
+
+/// The players of all configured variants
+pub struct Players {
+    pub default_variant: String,
+    pub variants: BTreeMap<String, Arc<Mutex<PlayerInfo>>>,
 }
 
 pub struct GlobalState {
-    pub player_info: Mutex<PlayerInfo>,
+    pub config: RwLock<Config>,
+    /// Notified when the configuration has been reloaded
+    pub config_changed: Notify,
+    /// Client for the requests to YouTube, rebuilt when the configuration is reloaded
+    pub upstream: RwLock<Arc<UpstreamClient>>,
+    pub players: RwLock<Players>,
+    /// Time of the next automatic player update, if enabled
+    pub next_update: Mutex<Option<SystemTime>>,
+    /// Start of the last player fetch from YouTube
+    pub last_upstream_fetch: Mutex<Option<Instant>>,
+    /// Result of the last player fetch from YouTube, `None` until it completes
+    pub last_upstream_result: Mutex<Option<Result<(), FetchUpdateStatus>>>,
+    /// The player update that is currently running, shared by all its requesters
+    pub update_in_flight: Mutex<Option<SharedUpdate>>,
+    /// Set while the active player is pinned and must not be replaced by updates
+    pub pinned: Mutex<bool>,
+    /// Player JS file or directory given with `--player-file`, YouTube is
+    /// never contacted if set
+    pub player_file: Option<PathBuf>,
+    /// The patterns the functions are extracted with
+    pub patterns: RwLock<Arc<Patterns>>,
     js_runtime_pool: Pool<Arc<JavascriptInterpreter>>,
 }
 
 impl GlobalState {
-    pub fn new() -> GlobalState {
-        let number_of_runtimes = available_parallelism()
-            .unwrap_or(NonZeroUsize::new(1).unwrap())
-            .get();
+    pub fn new(
+        config: Config,
+        upstream: UpstreamClient,
+        player_file: Option<PathBuf>,
+        patterns: Patterns,
+    ) -> GlobalState {
+        let number_of_runtimes = config.js_runtimes.unwrap_or_else(|| {
+            available_parallelism()
+                .unwrap_or(NonZeroUsize::new(1).unwrap())
+                .get()
+        })
+        .max(1);
         let mut runtime_vector: Vec<Arc<JavascriptInterpreter>> =
             Vec::with_capacity(number_of_runtimes);
         for _n in 0..number_of_runtimes {
@@ -103,19 +343,56 @@ impl GlobalState {
         }
 
         let runtime_pool: Pool<Arc<JavascriptInterpreter>> = Pool::from_vec(runtime_vector);
+        let mut players = Players {
+            default_variant: String::new(),
+            variants: BTreeMap::new(),
+        };
+        players.sync(&config);
         GlobalState {
-            player_info: Mutex::new(PlayerInfo {
-                nsig_function_code: Default::default(),
-                sig_function_code: Default::default(),
-                sig_function_name: Default::default(),
-                player_id: Default::default(),
-                signature_timestamp: Default::default(),
-                has_player: 0x00,
-                last_update: SystemTime::now(),
-            }),
+            config: RwLock::new(config),
+            config_changed: Notify::new(),
+            upstream: RwLock::new(Arc::new(upstream)),
+            players: RwLock::new(players),
+            next_update: Mutex::new(None),
+            last_upstream_fetch: Mutex::new(None),
+            last_upstream_result: Mutex::new(None),
+            update_in_flight: Mutex::new(None),
+            pinned: Mutex::new(false),
+            player_file,
+            patterns: RwLock::new(Arc::new(patterns)),
             js_runtime_pool: runtime_pool,
         }
     }
+
+    /// Returns the name and the player of `variant`, or of the default variant
+    pub async fn player(
+        &self,
+        variant: Option<&str>,
+    ) -> Option<(String, Arc<Mutex<PlayerInfo>>)> {
+        let players = self.players.read().await;
+        let variant = variant.unwrap_or(&players.default_variant);
+        let player = players.variants.get(variant)?.clone();
+        Some((variant.to_string(), player))
+    }
+}
+
+impl Players {
+    /// Adds the players of newly configured variants and removes the ones no
+    /// longer configured, keeping the players of the other variants. Returns
+    /// the added variants.
+    pub fn sync(&mut self, config: &Config) -> Vec<String> {
+        let variants = config.player_variants();
+        self.variants.retain(|name, _| variants.contains(name));
+        let mut added = Vec::new();
+        for variant in &variants {
+            if !self.variants.contains_key(variant) {
+                self.variants.insert(variant.clone(), Default::default());
+                added.push(variant.clone());
+            }
+        }
+        self.default_variant = variants[0].clone();
+        added
+    }
 }
 
 pub async fn process_fetch_update<W>(
@@ -140,9 +417,92 @@ pub async fn process_fetch_update<W>(
         .await;
 }
 
+/// Runs `PIN_PLAYER`, `UNPIN_PLAYER` or `ROLLBACK_PLAYER`, if the connection
+/// is allowed to
+pub async fn process_admin_command<W>(
+    state: Arc<GlobalState>,
+    opcode: Opcode,
+    allowed: bool,
+    stream: Arc<Mutex<W>>,
+) where
+    W: SinkExt<OpcodeResponse> + Unpin + Send,
+{
+    let status = match opcode.opcode {
+        _ if !allowed => {
+            warn!("Refused {} on a listener without admin access", opcode.opcode);
+            Err(FetchUpdateStatus::AdminNotAllowed)
+        }
+        JobOpcode::PinPlayer => {
+            let player_id = match opcode.player_id.is_empty() {
+                true => None,
+                false => Some(opcode.player_id),
+            };
+            pin_player(state, player_id).await
+        }
+        JobOpcode::UnpinPlayer => {
+            info!("Unpinned the player, updates are resumed");
+            *state.pinned.lock().await = false;
+            Ok(())
+        }
+        JobOpcode::RollbackPlayer => roll_back_player(state).await,
+        JobOpcode::OverridePlayer => match opcode.function_override {
+            Some(function_override) => {
+                override_player(state, opcode.variant, function_override).await
+            }
+            None => return,
+        },
+        JobOpcode::ClearOverride => clear_override(state, opcode.variant).await,
+        JobOpcode::ReloadPatterns => reload_patterns(state).await,
+        _ => return,
+    };
+
+    let mut writer = stream.lock().await;
+    let _ = writer
+        .send(OpcodeResponse {
+            opcode: opcode.opcode,
+            request_id: opcode.request_id,
+            update_status: status,
+            ..Default::default()
+        })
+        .await;
+}
+
+pub async fn process_dry_run_update<W>(
+    state: Arc<GlobalState>,
+    allowed: bool,
+    stream: Arc<Mutex<W>>,
+    request_id: u32,
+) where
+    W: SinkExt<OpcodeResponse> + Unpin + Send,
+{
+    let result = match allowed {
+        true => dry_run_update(state).await,
+        false => {
+            warn!("Refused DryRunUpdate on a listener without admin access");
+            Err(FetchUpdateStatus::AdminNotAllowed)
+        }
+    };
+    let (update_status, dry_run) = match result {
+        Ok(x) => (Ok(()), Some(x)),
+        Err(x) => (Err(x), None),
+    };
+
+    let mut writer = stream.lock().await;
+    let _ = writer
+        .send(OpcodeResponse {
+            opcode: JobOpcode::DryRunUpdate,
+            request_id,
+            update_status,
+            dry_run,
+            ..Default::default()
+        })
+        .await;
+}
+
 pub async fn process_decrypt_n_signature<W>(
     state: Arc<GlobalState>,
     sig: String,
+    variant: Option<String>,
     stream: Arc<Mutex<W>>,
     request_id: u32,
 ) where
@@ -151,17 +511,36 @@ pub async fn process_decrypt_n_signature<W>(
     let cloned_writer = stream.clone();
     let global_state = state.clone();
 
+    let (variant_name, player) = match global_state.player(variant.as_deref()).await {
+        Some(x) => x,
+        None => {
+            debug!("Unknown player variant: {:?}", variant);
+            let mut writer = cloned_writer.lock().await;
+            let _ = writer
+                .send(OpcodeResponse {
+                    opcode: JobOpcode::DecryptNSignature,
+                    request_id,
+                    ..Default::default()
+                })
+                .await;
+            return;
+        }
+    };
+
     //println!("Signature to be decrypted: {}", sig);
     let interp = global_state.js_runtime_pool.acquire().await;
+    let contexts = interp
+        .contexts(&variant_name, &*global_state.players.read().await)
+        .await;
 
-    let cloned_interp = interp.clone();
-    async_with!(cloned_interp.nsig_context => |ctx|{
+    let cloned_contexts = contexts.clone();
+    async_with!(cloned_contexts.nsig_context => |ctx|{
         let mut writer;
-        let mut current_player_id = interp.nsig_player_id.lock().await;
-        let player_info = global_state.player_info.lock().await;
+        let mut current_revision = contexts.nsig_revision.lock().await;
+        let player_info = player.lock().await;
 
-        if player_info.player_id != *current_player_id {
-            match ctx.eval::<(),String>(player_info.nsig_function_code.clone()) {
+        if player_info.revision != *current_revision {
+            match eval_player_code::<(),String>(&ctx, player_info.nsig_function_code.clone()) {
                 Ok(x) => x,
                 Err(n) => {
                     if n.is_exception() {
@@ -179,7 +558,7 @@ pub async fn process_decrypt_n_signature<W>(
                     return;
                 }
             }
-            *current_player_id = player_info.player_id;
+            *current_revision = player_info.revision;
         }
         drop(player_info);
 
@@ -223,6 +602,7 @@ pub async fn process_decrypt_n_signature<W>(
 pub async fn process_decrypt_signature<W>(
     state: Arc<GlobalState>,
     sig: String,
+    variant: Option<String>,
     stream: Arc<Mutex<W>>,
     request_id: u32,
 ) where
@@ -231,16 +611,35 @@ pub async fn process_decrypt_signature<W>(
     let cloned_writer = stream.clone();
     let global_state = state.clone();
 
+    let (variant_name, player) = match global_state.player(variant.as_deref()).await {
+        Some(x) => x,
+        None => {
+            debug!("Unknown player variant: {:?}", variant);
+            let mut writer = cloned_writer.lock().await;
+            let _ = writer
+                .send(OpcodeResponse {
+                    opcode: JobOpcode::DecryptSignature,
+                    request_id,
+                    ..Default::default()
+                })
+                .await;
+            return;
+        }
+    };
+
     let interp = global_state.js_runtime_pool.acquire().await;
-    let cloned_interp = interp.clone();
+    let contexts = interp
+        .contexts(&variant_name, &*global_state.players.read().await)
+        .await;
+    let cloned_contexts = contexts.clone();
 
-    async_with!(cloned_interp.sig_context => |ctx|{
+    async_with!(cloned_contexts.sig_context => |ctx|{
         let mut writer;
-        let mut current_player_id = interp.sig_player_id.lock().await;
-        let player_info = global_state.player_info.lock().await;
+        let mut current_revision = contexts.sig_revision.lock().await;
+        let player_info = player.lock().await;
 
-        if player_info.player_id != *current_player_id {
-            match ctx.eval::<(),String>(player_info.sig_function_code.clone()) {
+        if player_info.revision != *current_revision {
+            match eval_player_code::<(),String>(&ctx, player_info.sig_function_code.clone()) {
                 Ok(x) => x,
                 Err(n) => {
                     if n.is_exception() {
@@ -258,7 +657,7 @@ pub async fn process_decrypt_signature<W>(
                     return;
                 }
             }
-            *current_player_id = player_info.player_id;
+            *current_revision = player_info.revision;
         }
 
         let sig_function_name = &player_info.sig_function_name;
@@ -304,6 +703,7 @@ pub async fn process_decrypt_signature<W>(
 
 pub async fn process_get_signature_timestamp<W>(
     state: Arc<GlobalState>,
+    variant: Option<String>,
     stream: Arc<Mutex<W>>,
     request_id: u32,
 ) where
@@ -312,8 +712,13 @@ pub async fn process_get_signature_timestamp<W>(
     let cloned_writer = stream.clone();
     let global_state = state.clone();
 
-    let player_info = global_state.player_info.lock().await;
-    let timestamp = player_info.signature_timestamp;
+    let timestamp = match global_state.player(variant.as_deref()).await {
+        Some((_, player)) => player.lock().await.signature_timestamp,
+        None => {
+            debug!("Unknown player variant: {:?}", variant);
+            0
+        }
+    };
 
     let mut writer = cloned_writer.lock().await;
     let _ = writer
@@ -326,8 +731,10 @@ pub async fn process_get_signature_timestamp<W>(
         .await;
 }
 
+/// Answers `PLAYER_STATUS`, or `PLAYER_DETAILS` with the same data and more
 pub async fn process_player_status<W>(
     state: Arc<GlobalState>,
+    opcode: JobOpcode,
     stream: Arc<Mutex<W>>,
     request_id: u32,
 ) where
@@ -336,18 +743,37 @@ pub async fn process_player_status<W>(
     let cloned_writer = stream.clone();
     let global_state = state.clone();
 
-    let player_info = global_state.player_info.lock().await;
+    let (_, player) = global_state.player(None).await.unwrap();
+    let player_info = player.lock().await;
     let has_player = player_info.has_player;
     let player_id = player_info.player_id;
+    let player_url = player_info.player_url.clone();
+    let discovery_source = player_info.discovery_source.clone();
+    let overridden = player_info.overridden.is_some();
+    drop(player_info);
+    let pinned = *global_state.pinned.lock().await;
+
+    let next_update = match *global_state.next_update.lock().await {
+        Some(x) => x
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_secs(),
+        None => 0,
+    };
 
     let mut writer = cloned_writer.lock().await;
 
     let _ = writer
         .send(OpcodeResponse {
-            opcode: JobOpcode::PlayerStatus,
+            opcode,
             request_id,
             has_player,
             player_id,
+            next_update,
+            player_url,
+            discovery_source,
+            pinned,
+            overridden,
             ..Default::default()
         })
         .await;
@@ -363,8 +789,8 @@ pub async fn process_player_update_timestamp<W>(
     let cloned_writer = stream.clone();
     let global_state = state.clone();
 
-    let player_info = global_state.player_info.lock().await;
-    let last_update = player_info.last_update;
+    let (_, player) = global_state.player(None).await.unwrap();
+    let last_update = player.lock().await.last_update;
 
     let mut writer = cloned_writer.lock().await;
 
@@ -372,12 +798,43 @@ pub async fn process_player_update_timestamp<W>(
         .send(OpcodeResponse {
             opcode: JobOpcode::PlayerUpdateTimestamp,
             request_id,
+            // cached players can come from a host whose clock is ahead
             last_player_update: SystemTime::now()
                 .duration_since(last_update)
-                .unwrap()
+                .unwrap_or_default()
                 .as_secs(),
 
             ..Default::default()
         })
         .await;
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn players(variants: &[&str]) -> Players {
+        Players {
+            default_variant: variants[0].to_string(),
+            variants: variants
+                .iter()
+                .map(|x| (x.to_string(), Default::default()))
+                .collect(),
+        }
+    }
+
+    #[tokio::test]
+    async fn contexts_of_removed_variants_are_dropped() {
+        let interpreter = JavascriptInterpreter::new();
+        let both = players(&["main", "tv"]);
+        let tv = interpreter.contexts("tv", &both).await;
+        assert!(Arc::ptr_eq(&tv, &interpreter.contexts("tv", &both).await));
+        interpreter.contexts("main", &both).await;
+
+        interpreter.contexts("main", &players(&["main"])).await;
+        assert_eq!(interpreter.contexts.lock().await.len(), 1);
+        // a variant added again starts from fresh contexts
+        let tv_again = interpreter.contexts("tv", &both).await;
+        assert!(!Arc::ptr_eq(&tv, &tv_again));
+    }
+}
@@ -0,0 +1,49 @@
+use std::sync::{OnceLock, RwLock};
+
+use log::{Log, Metadata, Record};
+
+// env_logger fixes its filter when it is built, so the logger is wrapped to
+// allow swapping it out when the configuration is reloaded
+struct ReloadableLogger {
+    inner: RwLock<env_logger::Logger>,
+}
+
+static LOGGER: OnceLock<ReloadableLogger> = OnceLock::new();
+
+fn build(filter: &str) -> env_logger::Logger {
+    env_logger::Builder::new()
+        .parse_filters(filter)
+        .target(env_logger::Target::Stderr)
+        .build()
+}
+
+impl Log for ReloadableLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        self.inner.read().unwrap().enabled(metadata)
+    }
+
+    fn log(&self, record: &Record) {
+        self.inner.read().unwrap().log(record)
+    }
+
+    fn flush(&self) {
+        self.inner.read().unwrap().flush()
+    }
+}
+
+pub fn init(filter: &str) {
+    let logger = build(filter);
+    log::set_max_level(logger.filter());
+    let _ = LOGGER.set(ReloadableLogger {
+        inner: RwLock::new(logger),
+    });
+    let _ = log::set_logger(LOGGER.get().unwrap());
+}
+
+pub fn set_filter(filter: &str) {
+    let logger = build(filter);
+    log::set_max_level(logger.filter());
+    if let Some(x) = LOGGER.get() {
+        *x.inner.write().unwrap() = logger;
+    }
+}
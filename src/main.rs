@@ -1,74 +1,299 @@
+mod ast;
+mod cache;
+mod config;
 mod consts;
+mod fixups;
 mod jobs;
+mod logger;
 mod opcode;
+mod patterns;
 mod player;
+mod selftest;
+mod source;
+mod updater;
+mod upstream;
 
 use ::futures::StreamExt;
+use config::{Config, ListenerConfig, ListenerKind};
 use consts::{DEFAULT_SOCK_PATH, DEFAULT_SOCK_PERMS, DEFAULT_TCP_URL};
 use jobs::{process_decrypt_n_signature, process_fetch_update, GlobalState, JobOpcode};
 use opcode::OpcodeDecoder;
-use player::fetch_update;
-use std::{env::args, sync::Arc, fs::set_permissions, fs::Permissions, os::unix::fs::PermissionsExt};
-use env_logger::Env;
+use patterns::Patterns;
+use player::{fetch_update, reload_patterns};
+use std::{
+    collections::HashMap, env::args, fs::set_permissions, fs::Permissions,
+    os::unix::fs::PermissionsExt, path::PathBuf, sync::Arc,
+};
 use tokio::{
     fs::remove_file,
-    io::{AsyncReadExt, AsyncWrite},
+    io::{join, stdin, stdout, AsyncReadExt, AsyncWrite},
     net::{TcpListener, UnixListener},
+    signal::unix::{signal, SignalKind},
     sync::Mutex,
+    task::{JoinHandle, JoinSet},
 };
 use tokio_util::codec::Framed;
-use log::{info, error, debug};
+use updater::run_update_scheduler;
+use upstream::UpstreamClient;
+use log::{info, error, debug, warn};
 
 use crate::jobs::{
-    process_decrypt_signature, process_get_signature_timestamp, process_player_status,
+    process_admin_command, process_decrypt_signature, process_dry_run_update, process_get_signature_timestamp, process_player_status,
     process_player_update_timestamp,
 };
 
-macro_rules! loop_main {
-    ($i:ident, $s:ident) => {
-        info!("Fetching player");
-        match fetch_update($s.clone()).await {
-            Ok(()) => info!("Successfully fetched player"),
-            Err(x) => {
-                error!("Error occured while trying to fetch the player: {:?}", x);
+macro_rules! accept_loop {
+    ($i:ident, $s:ident, $a:ident) => {
+        tokio::spawn(async move {
+            loop {
+                let socket = match $i.accept().await {
+                    Ok((socket, _addr)) => socket,
+                    Err(x) => {
+                        error!("Error occurred while accepting a connection: {}", x);
+                        continue;
+                    }
+                };
+
+                let cloned_state = $s.clone();
+                tokio::spawn(async move {
+                    process_socket(cloned_state, socket, $a).await;
+                });
+            }
+        })
+    };
+}
+
+async fn bind_listener(
+    listener: &ListenerConfig,
+    state: Arc<GlobalState>,
+) -> std::io::Result<JoinHandle<()>> {
+    let admin = listener.admin;
+    match listener.kind() {
+        ListenerKind::Tcp(addr) => {
+            let tcp_socket = TcpListener::bind(addr).await?;
+            Ok(accept_loop!(tcp_socket, state, admin))
+        }
+        ListenerKind::Unix(path) => {
+            let unix_socket = match UnixListener::bind(path) {
+                Ok(x) => x,
+                Err(x) if x.kind() == std::io::ErrorKind::AddrInUse => {
+                    let _ = remove_file(path).await;
+                    UnixListener::bind(path)?
+                }
+                Err(x) => return Err(x),
+            };
+            let perms = Permissions::from_mode(listener.permissions.unwrap_or(DEFAULT_SOCK_PERMS));
+            let _ = set_permissions(path, perms);
+            Ok(accept_loop!(unix_socket, state, admin))
+        }
+    }
+}
+
+#[derive(Default)]
+struct ListenerSet {
+    running: HashMap<ListenerConfig, JoinHandle<()>>,
+}
+
+impl ListenerSet {
+    /// Stops the listeners that are not wanted anymore and binds the new ones.
+    /// Returns false if any of the new listeners could not be bound.
+    async fn apply(&mut self, wanted: &[ListenerConfig], state: &Arc<GlobalState>) -> bool {
+        let stale: Vec<ListenerConfig> = self
+            .running
+            .keys()
+            .filter(|x| !wanted.contains(x))
+            .cloned()
+            .collect();
+        for listener in stale {
+            info!("Stopping listener {}", listener.address);
+            if let Some(handle) = self.running.remove(&listener) {
+                handle.abort();
+            }
+            if let ListenerKind::Unix(path) = listener.kind() {
+                let _ = remove_file(path).await;
             }
         }
-        loop {
-            let (socket, _addr) = $i.accept().await.unwrap();
 
-            let cloned_state = $s.clone();
-            tokio::spawn(async move {
-                process_socket(cloned_state, socket).await;
-            });
+        let mut all_bound = true;
+        for listener in wanted {
+            if self.running.contains_key(listener) {
+                continue;
+            }
+            match bind_listener(listener, state.clone()).await {
+                Ok(handle) => {
+                    info!("Listening on {}", listener.address);
+                    self.running.insert(listener.clone(), handle);
+                }
+                Err(x) => {
+                    error!("Error occurred while trying to bind {}: {}", listener.address, x);
+                    all_bound = false;
+                }
+            }
+        }
+        all_bound
+    }
+}
+
+/// Removes `name` and the value following it from the arguments
+fn take_option(args: &mut Vec<String>, name: &str) -> Option<String> {
+    let index = args.iter().position(|x| x == name)?;
+    args.remove(index);
+    if index < args.len() {
+        Some(args.remove(index))
+    } else {
+        None
+    }
+}
+
+async fn reload_config(
+    config_path: &Option<String>,
+    cli_listener: &ListenerConfig,
+    state: &Arc<GlobalState>,
+    listeners: Option<&mut ListenerSet>,
+) {
+    let path = match config_path {
+        Some(x) => x,
+        None => {
+            warn!("Received SIGHUP but no configuration file was given, nothing to reload");
+            return;
+        }
+    };
+    let new_config = match Config::load(path) {
+        Ok(x) => x,
+        Err(x) => {
+            error!("Keeping the current configuration, {}", x);
+            return;
         }
     };
+    info!("Reloading configuration from {}", path);
+
+    let mut config = state.config.write().await;
+    // a new client would lose the cookies and the cached responses
+    if new_config.upstream_changed(&config) {
+        match UpstreamClient::new(&new_config) {
+            Ok(x) => *state.upstream.write().await = Arc::new(x),
+            Err(x) => {
+                error!("Keeping the current configuration, could not create the HTTP client: {}", x);
+                return;
+            }
+        }
+    }
+    for setting in new_config.restart_required(&config) {
+        warn!("Setting '{}' changed, it will only take effect after a restart", setting);
+    }
+    let patterns_changed = new_config.patterns_changed(&config);
+    logger::set_filter(&new_config.log_filter());
+    *config = new_config;
+    let added = state.players.write().await.sync(&config);
+    let wanted = listeners_for(&config, cli_listener);
+    drop(config);
+    state.config_changed.notify_one();
+
+    // stdio mode has no listeners to change
+    if let Some(listeners) = listeners {
+        listeners.apply(&wanted, state).await;
+    }
+    // extracting the functions of every variant again takes seconds
+    if patterns_changed {
+        let _ = reload_patterns(state.clone()).await;
+    }
+
+    if !added.is_empty() {
+        info!("Fetching the players of the new variants {}", added.join(", "));
+        // the new variants would have no player until the next scheduled update
+        *state.last_upstream_fetch.lock().await = None;
+        tokio::spawn(initial_fetch(state.clone()));
+    }
 }
+
+fn listeners_for(config: &Config, cli_listener: &ListenerConfig) -> Vec<ListenerConfig> {
+    if config.listeners.is_empty() {
+        vec![cli_listener.clone()]
+    } else {
+        config.listeners.clone()
+    }
+}
+
 #[tokio::main]
 async fn main() {
-    env_logger::Builder::from_env(Env::default().default_filter_or("info")).init();
+    let mut args: Vec<String> = args().collect();
+    let config_path = take_option(&mut args, "--config");
+    let player_file = take_option(&mut args, "--player-file").map(PathBuf::from);
+
+    let config = match &config_path {
+        Some(path) => match Config::load(path) {
+            Ok(x) => x,
+            Err(x) => {
+                eprintln!("{}: {}", path, x);
+                std::process::exit(1);
+            }
+        },
+        None => Config::default(),
+    };
+    logger::init(&config.log_filter());
+    let patterns = match Patterns::from_config(&config) {
+        Ok(x) => x,
+        Err(x) => {
+            eprintln!("{}: {}", config.patterns_file.as_deref().unwrap_or_default(), x);
+            std::process::exit(1);
+        }
+    };
 
-    let args: Vec<String> = args().collect();
     let socket_url: &str = match args.get(1) {
         Some(stringref) => stringref,
         None => DEFAULT_SOCK_PATH,
     };
 
-    // have to please rust
-    let state: Arc<GlobalState> = Arc::new(GlobalState::new());
-
-    if socket_url == "--tcp" {
+    let cli_listener = if socket_url == "--tcp" {
         let socket_tcp_url: &str = match args.get(2) {
             Some(stringref) => stringref,
             None => DEFAULT_TCP_URL,
         };
-        let tcp_socket = match TcpListener::bind(socket_tcp_url).await {
-            Ok(x) => x,
-            Err(x) => {
-                error!("Error occurred while trying to bind: {}", x);
-                return;
-            }
+        ListenerConfig {
+            address: format!("tcp:{}", socket_tcp_url),
+            permissions: None,
+            admin: false,
+        }
+    } else {
+        let socket_perms: u32 = match args.get(2) {
+            Some(stringref) => u32::from_str_radix(stringref, 8).expect(
+                "Socket permissions must be an octal from 0 to 777!"),
+            None => DEFAULT_SOCK_PERMS,
         };
-        loop_main!(tcp_socket, state);
+        ListenerConfig {
+            address: socket_url.to_string(),
+            permissions: Some(socket_perms),
+            admin: false,
+        }
+    };
+    let wanted = listeners_for(&config, &cli_listener);
+
+    let upstream = match UpstreamClient::new(&config) {
+        Ok(x) => x,
+        Err(x) => {
+            error!("Could not create the HTTP client: {}", x);
+            return;
+        }
+    };
+
+    // have to please rust
+    let state: Arc<GlobalState> = Arc::new(GlobalState::new(config, upstream, player_file, patterns));
+    // registered before the first fetch, a SIGHUP would kill the process until then
+    let mut hangup = signal(SignalKind::hangup()).unwrap();
+
+    if socket_url == "--stdio" {
+        let reload_state = state.clone();
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                reload_config(&config_path, &cli_listener, &reload_state, None).await;
+            }
+        });
+        // stdout carries the responses, so logs have to stay on stderr
+        load_player(state.clone()).await;
+        tokio::spawn(run_update_scheduler(state.clone()));
+        // only the parent process can talk to the helper
+        process_socket(state, join(stdin(), stdout()), true).await;
+        return;
     } else if socket_url == "--test" {
         // TODO: test the API aswell, this only tests the player script extractor
         info!("Fetching player");
@@ -76,31 +301,42 @@ async fn main() {
             Ok(()) => std::process::exit(0),
             Err(_x) => std::process::exit(-1),
         }
+    }
+
+    load_player(state.clone()).await;
+
+    let mut listeners = ListenerSet::default();
+    if !listeners.apply(&wanted, &state).await {
+        return;
+    }
+    tokio::spawn(run_update_scheduler(state.clone()));
+
+    while hangup.recv().await.is_some() {
+        reload_config(&config_path, &cli_listener, &state, Some(&mut listeners)).await;
+    }
+}
+
+async fn initial_fetch(state: Arc<GlobalState>) {
+    info!("Fetching player");
+    match fetch_update(state).await {
+        Ok(()) => info!("Successfully fetched player"),
+        Err(x) => {
+            error!("Error occured while trying to fetch the player: {:?}", x);
+        }
+    }
+}
+
+/// Starts from the cached player if there is one and refreshes it in the
+/// background, otherwise waits for the player to be fetched
+async fn load_player(state: Arc<GlobalState>) {
+    if cache::load_players(&state).await {
+        tokio::spawn(initial_fetch(state));
     } else {
-        let unix_socket = match UnixListener::bind(socket_url) {
-            Ok(x) => x,
-            Err(x) => {
-                if x.kind() == std::io::ErrorKind::AddrInUse {
-                    remove_file(socket_url).await;
-                    UnixListener::bind(socket_url).unwrap()
-                } else {
-                    error!("Error occurred while trying to bind: {}", x);
-                    return;
-                }
-            }
-        };
-        let socket_perms: u32 = match args.get(2) {
-            Some(stringref) => u32::from_str_radix(stringref, 8).expect(
-                "Socket permissions must be an octal from 0 to 777!"),
-            None => DEFAULT_SOCK_PERMS,
-        };
-        let perms = Permissions::from_mode(socket_perms);
-        let _ = set_permissions(socket_url, perms);
-        loop_main!(unix_socket, state);
+        initial_fetch(state).await;
     }
 }
 
-async fn process_socket<W>(state: Arc<GlobalState>, socket: W)
+async fn process_socket<W>(state: Arc<GlobalState>, socket: W, admin: bool)
 where
     W: AsyncReadExt + Send + AsyncWrite + 'static,
 {
@@ -110,7 +346,9 @@ where
     let (sink, mut stream) = str.split();
 
     let arc_sink = Arc::new(Mutex::new(sink));
+    let mut tasks = JoinSet::new();
     while let Some(opcode_res) = stream.next().await {
+        while tasks.try_join_next().is_some() {}
         match opcode_res {
             Ok(opcode) => {
                 debug!("Received job: {}", opcode.opcode);
@@ -119,61 +357,69 @@ where
                     JobOpcode::ForceUpdate => {
                         let cloned_state = state.clone();
                         let cloned_sink = arc_sink.clone();
-                        tokio::spawn(async move {
+                        tasks.spawn(async move {
                             process_fetch_update(cloned_state, cloned_sink, opcode.request_id)
                                 .await;
                         });
                     }
-                    JobOpcode::DecryptNSignature => {
+                    JobOpcode::DecryptNSignature | JobOpcode::DecryptNSignatureVariant => {
                         let cloned_state = state.clone();
                         let cloned_sink = arc_sink.clone();
-                        tokio::spawn(async move {
+                        tasks.spawn(async move {
                             process_decrypt_n_signature(
                                 cloned_state,
                                 opcode.signature,
+                                opcode.variant,
                                 cloned_sink,
                                 opcode.request_id,
                             )
                             .await;
                         });
                     }
-                    JobOpcode::DecryptSignature => {
+                    JobOpcode::DecryptSignature | JobOpcode::DecryptSignatureVariant => {
                         let cloned_state = state.clone();
                         let cloned_sink = arc_sink.clone();
-                        tokio::spawn(async move {
+                        tasks.spawn(async move {
                             process_decrypt_signature(
                                 cloned_state,
                                 opcode.signature,
+                                opcode.variant,
                                 cloned_sink,
                                 opcode.request_id,
                             )
                             .await;
                         });
                     }
-                    JobOpcode::GetSignatureTimestamp => {
+                    JobOpcode::GetSignatureTimestamp | JobOpcode::GetSignatureTimestampVariant => {
                         let cloned_state = state.clone();
                         let cloned_sink = arc_sink.clone();
-                        tokio::spawn(async move {
+                        tasks.spawn(async move {
                             process_get_signature_timestamp(
                                 cloned_state,
+                                opcode.variant,
                                 cloned_sink,
                                 opcode.request_id,
                             )
                             .await;
                         });
                     }
-                    JobOpcode::PlayerStatus => {
+                    JobOpcode::PlayerStatus | JobOpcode::PlayerDetails => {
                         let cloned_state = state.clone();
                         let cloned_sink = arc_sink.clone();
-                        tokio::spawn(async move {
-                            process_player_status(cloned_state, cloned_sink, opcode.request_id)
-                                .await;
+                        tasks.spawn(async move {
+                            process_player_status(
+                                cloned_state,
+                                opcode.opcode,
+                                cloned_sink,
+                                opcode.request_id,
+                            )
+                            .await;
                         });
                     }
                     JobOpcode::PlayerUpdateTimestamp => {
                         let cloned_state = state.clone();
                         let cloned_sink = arc_sink.clone();
-                        tokio::spawn(async move {
+                        tasks.spawn(async move {
                             process_player_update_timestamp(
                                 cloned_state,
                                 cloned_sink,
@@ -182,6 +428,31 @@ where
                             .await;
                         });
                     }
+                    JobOpcode::DryRunUpdate => {
+                        let cloned_state = state.clone();
+                        let cloned_sink = arc_sink.clone();
+                        tasks.spawn(async move {
+                            process_dry_run_update(
+                                cloned_state,
+                                admin,
+                                cloned_sink,
+                                opcode.request_id,
+                            )
+                            .await;
+                        });
+                    }
+                    JobOpcode::PinPlayer
+                    | JobOpcode::UnpinPlayer
+                    | JobOpcode::RollbackPlayer
+                    | JobOpcode::OverridePlayer
+                    | JobOpcode::ClearOverride
+                    | JobOpcode::ReloadPatterns => {
+                        let cloned_state = state.clone();
+                        let cloned_sink = arc_sink.clone();
+                        tasks.spawn(async move {
+                            process_admin_command(cloned_state, opcode, admin, cloned_sink).await;
+                        });
+                    }
                     _ => {
                         continue;
                     }
@@ -193,4 +464,7 @@ where
             }
         }
     }
+
+    // answer the jobs that are still running before closing the connection
+    while tasks.join_next().await.is_some() {}
 }
@@ -5,7 +5,11 @@ use tokio_util::{
     codec::{Decoder, Encoder},
 };
 
-use crate::{jobs::JobOpcode, player::FetchUpdateStatus};
+use crate::{
+    consts::MAX_OVERRIDE_CODE_SIZE,
+    jobs::{FunctionOverride, JobOpcode},
+    player::{DryRunReport, FetchUpdateStatus},
+};
 
 #[derive(Copy, Clone)]
 pub struct OpcodeDecoder {}
@@ -15,6 +19,11 @@ pub struct Opcode {
     pub request_id: u32,
 
     pub signature: String,
+    /// Player variant chosen by the client, the default variant if `None`
+    pub variant: Option<String>,
+    /// Player id given to `PIN_PLAYER`, empty for the active player
+    pub player_id: String,
+    pub function_override: Option<FunctionOverride>,
 }
 
 pub struct OpcodeResponse {
@@ -28,6 +37,12 @@ pub struct OpcodeResponse {
     pub has_player: u8,
     pub player_id: u32,
     pub last_player_update: u64,
+    pub next_update: u64,
+    pub player_url: String,
+    pub discovery_source: String,
+    pub pinned: bool,
+    pub overridden: bool,
+    pub dry_run: Option<DryRunReport>,
 }
 
 impl Default for OpcodeResponse {
@@ -41,9 +56,94 @@ impl Default for OpcodeResponse {
             has_player: 0,
             player_id: 0,
             last_player_update: 0,
+            next_update: 0,
+            player_url: String::new(),
+            discovery_source: String::new(),
+            pinned: false,
+            overridden: false,
+            dry_run: None,
         }
     }
 }
+/// The longest prefix of `x` that fits in a string prefixed with its 16-bit
+/// size, cut on a character boundary
+fn truncated(x: &str) -> &str {
+    let mut end = x.len().min(usize::from(u16::MAX));
+    while !x.is_char_boundary(end) {
+        end -= 1;
+    }
+    &x[..end]
+}
+
+/// Reads a string prefixed with its 16-bit size at `offset`, returns the string
+/// and the offset after it, or `None` if `src` does not contain all of it yet
+fn read_string(src: &[u8], offset: usize) -> Result<Option<(String, usize)>, std::io::Error> {
+    if offset + 2 > src.len() {
+        return Ok(None);
+    }
+    let size = usize::from(u16::from_be_bytes([src[offset], src[offset + 1]]));
+    let end = offset + 2 + size;
+    if end > src.len() {
+        return Ok(None);
+    }
+    match String::from_utf8(src[offset + 2..end].to_vec()) {
+        Ok(x) => Ok(Some((x, end))),
+        Err(x) => Err(std::io::Error::new(ErrorKind::InvalidData, x.utf8_error())),
+    }
+}
+
+/// Like `read_string`, for strings prefixed with their 32-bit size
+fn read_long_string(src: &[u8], offset: usize) -> Result<Option<(String, usize)>, std::io::Error> {
+    if offset + 4 > src.len() {
+        return Ok(None);
+    }
+    let size = u32::from_be_bytes(src[offset..offset + 4].try_into().unwrap()) as usize;
+    if size > MAX_OVERRIDE_CODE_SIZE {
+        return Err(std::io::Error::new(ErrorKind::InvalidData, "string is too long"));
+    }
+    let end = offset + 4 + size;
+    if end > src.len() {
+        return Ok(None);
+    }
+    match String::from_utf8(src[offset + 4..end].to_vec()) {
+        Ok(x) => Ok(Some((x, end))),
+        Err(x) => Err(std::io::Error::new(ErrorKind::InvalidData, x.utf8_error())),
+    }
+}
+
+/// Reads the functions of an `OVERRIDE_PLAYER` request starting at `offset`,
+/// returns them and the offset after them
+fn read_function_override(
+    src: &[u8],
+    offset: usize,
+) -> Result<Option<(FunctionOverride, usize)>, std::io::Error> {
+    let (nsig_function_code, end) = match read_long_string(src, offset)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let (sig_function_name, end) = match read_string(src, end)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    let (sig_function_code, end) = match read_long_string(src, end)? {
+        Some(x) => x,
+        None => return Ok(None),
+    };
+    if end + 8 > src.len() {
+        return Ok(None);
+    }
+    let signature_timestamp = u64::from_be_bytes(src[end..end + 8].try_into().unwrap());
+    Ok(Some((
+        FunctionOverride {
+            nsig_function_code,
+            sig_function_name,
+            sig_function_code,
+            signature_timestamp,
+        },
+        end + 8,
+    )))
+}
+
 impl Decoder for OpcodeDecoder {
     type Item = Opcode;
     type Error = std::io::Error;
@@ -65,12 +165,20 @@ impl Decoder for OpcodeDecoder {
             JobOpcode::ForceUpdate
             | JobOpcode::GetSignatureTimestamp
             | JobOpcode::PlayerStatus
-            | JobOpcode::PlayerUpdateTimestamp => {
+            | JobOpcode::PlayerDetails
+            | JobOpcode::PlayerUpdateTimestamp
+            | JobOpcode::UnpinPlayer
+            | JobOpcode::RollbackPlayer
+            | JobOpcode::DryRunUpdate
+            | JobOpcode::ReloadPatterns => {
                 src.advance(5);
                 Ok(Some(Opcode {
                     opcode,
                     request_id,
                     signature: Default::default(),
+                    variant: None,
+                    player_id: Default::default(),
+                    function_override: None,
                 }))
             }
             JobOpcode::DecryptSignature | JobOpcode::DecryptNSignature => {
@@ -101,6 +209,82 @@ impl Decoder for OpcodeDecoder {
                     opcode,
                     request_id,
                     signature: sig,
+                    variant: None,
+                    player_id: Default::default(),
+                    function_override: None,
+                }))
+            }
+            JobOpcode::DecryptNSignatureVariant
+            | JobOpcode::DecryptSignatureVariant
+            | JobOpcode::GetSignatureTimestampVariant => {
+                let (variant, mut end) = match read_string(src, 5)? {
+                    Some(x) => x,
+                    None => return Ok(None),
+                };
+
+                let sig: String = match opcode {
+                    JobOpcode::GetSignatureTimestampVariant => String::new(),
+                    _ => match read_string(src, end)? {
+                        Some((sig, sig_end)) => {
+                            end = sig_end;
+                            sig
+                        }
+                        None => return Ok(None),
+                    },
+                };
+
+                src.advance(end);
+
+                Ok(Some(Opcode {
+                    opcode,
+                    request_id,
+                    signature: sig,
+                    variant: Some(variant),
+                    player_id: Default::default(),
+                    function_override: None,
+                }))
+            }
+            JobOpcode::OverridePlayer | JobOpcode::ClearOverride => {
+                let (variant, mut end) = match read_string(src, 5)? {
+                    Some(x) => x,
+                    None => return Ok(None),
+                };
+                let function_override = match opcode {
+                    JobOpcode::OverridePlayer => match read_function_override(src, end)? {
+                        Some((function_override, override_end)) => {
+                            end = override_end;
+                            Some(function_override)
+                        }
+                        None => return Ok(None),
+                    },
+                    _ => None,
+                };
+                src.advance(end);
+
+                Ok(Some(Opcode {
+                    opcode,
+                    request_id,
+                    signature: Default::default(),
+                    // the default variant if empty
+                    variant: Some(variant).filter(|x| !x.is_empty()),
+                    player_id: Default::default(),
+                    function_override,
+                }))
+            }
+            JobOpcode::PinPlayer => {
+                let (player_id, end) = match read_string(src, 5)? {
+                    Some(x) => x,
+                    None => return Ok(None),
+                };
+                src.advance(end);
+
+                Ok(Some(Opcode {
+                    opcode,
+                    request_id,
+                    signature: Default::default(),
+                    variant: None,
+                    player_id,
+                    function_override: None,
                 }))
             }
             _ => Err(std::io::Error::new(ErrorKind::InvalidInput, "")),
@@ -117,19 +301,38 @@ impl Encoder<OpcodeResponse> for OpcodeDecoder {
     ) -> Result<(), Self::Error> {
         dst.put_u32(item.request_id);
         match item.opcode {
-            JobOpcode::ForceUpdate => {
-                dst.put_u32(2);
+            JobOpcode::ForceUpdate
+            | JobOpcode::PinPlayer
+            | JobOpcode::UnpinPlayer
+            | JobOpcode::RollbackPlayer
+            | JobOpcode::OverridePlayer
+            | JobOpcode::ClearOverride
+            | JobOpcode::ReloadPatterns => {
+                dst.put_u32(4);
                 match item.update_status {
-                    Ok(_x) => dst.put_u16(0xF44F),
-                    Err(FetchUpdateStatus::PlayerAlreadyUpdated) => dst.put_u16(0xFFFF),
-                    Err(_x) => dst.put_u16(0x0000),
+                    Ok(_x) => {
+                        dst.put_u16(0xF44F);
+                        dst.put_u16(0x0000);
+                    }
+                    Err(
+                        x @ (FetchUpdateStatus::PlayerAlreadyUpdated
+                        | FetchUpdateStatus::PlayerPinned),
+                    ) => {
+                        dst.put_u16(0xFFFF);
+                        dst.put_u16(x.code());
+                    }
+                    Err(x) => {
+                        dst.put_u16(0x0000);
+                        dst.put_u16(x.code());
+                    }
                 }
             }
             JobOpcode::DecryptSignature | JobOpcode::DecryptNSignature => {
-                dst.put_u32(2 + u32::try_from(item.signature.len()).unwrap());
-                dst.put_u16(u16::try_from(item.signature.len()).unwrap());
-                if !item.signature.is_empty() {
-                    dst.put_slice(item.signature.as_bytes());
+                let signature = truncated(&item.signature);
+                dst.put_u32(2 + u32::try_from(signature.len()).unwrap());
+                dst.put_u16(u16::try_from(signature.len()).unwrap());
+                if !signature.is_empty() {
+                    dst.put_slice(signature.as_bytes());
                 }
             }
             JobOpcode::GetSignatureTimestamp => {
@@ -141,12 +344,253 @@ impl Encoder<OpcodeResponse> for OpcodeDecoder {
                 dst.put_u8(item.has_player);
                 dst.put_u32(item.player_id);
             }
+            JobOpcode::PlayerDetails => {
+                let player_url = truncated(&item.player_url);
+                let discovery_source = truncated(&item.discovery_source);
+                dst.put_u32(
+                    19 + u32::try_from(player_url.len() + discovery_source.len()).unwrap(),
+                );
+                dst.put_u8(item.has_player);
+                dst.put_u32(item.player_id);
+                dst.put_u64(item.next_update);
+                dst.put_u16(u16::try_from(player_url.len()).unwrap());
+                dst.put_slice(player_url.as_bytes());
+                dst.put_u16(u16::try_from(discovery_source.len()).unwrap());
+                dst.put_slice(discovery_source.as_bytes());
+                dst.put_u8(u8::from(item.pinned));
+                dst.put_u8(u8::from(item.overridden));
+            }
             JobOpcode::PlayerUpdateTimestamp => {
                 dst.put_u32(8);
                 dst.put_u64(item.last_player_update);
             }
+            JobOpcode::DryRunUpdate => match (item.update_status, item.dry_run) {
+                (Ok(()), Some(report)) => {
+                    let id = truncated(&report.player_url.id).as_bytes();
+                    let url = truncated(&report.player_url.url).as_bytes();
+                    // the self-test error contains the output of the player code
+                    let self_test_error = report.self_test_error.unwrap_or_default();
+                    let self_test_error = truncated(&self_test_error);
+                    let unresolved = report.extracted.unresolved.join(",");
+                    let unresolved = truncated(&unresolved);
+                    dst.put_u32(
+                        24 + u32::try_from(
+                            id.len() + url.len() + self_test_error.len() + unresolved.len(),
+                        )
+                        .unwrap(),
+                    );
+                    dst.put_u16(0xF44F);
+                    dst.put_u16(0x0000);
+                    dst.put_u16(u16::try_from(id.len()).unwrap());
+                    dst.put_slice(id);
+                    dst.put_u16(u16::try_from(url.len()).unwrap());
+                    dst.put_slice(url);
+                    // 0xFE marks a function found by the parser, 0xFD one
+                    // exported from the whole player, 0xFC one found by the search
+                    let (nsig_array, nsig_ending) = match report.extracted.nsig_patterns {
+                        _ if report.extracted.full_player => (0xFD, 0xFD),
+                        _ if report.extracted.nsig_searched => (0xFC, 0xFC),
+                        Some((array, ending)) => (
+                            u8::try_from(array).unwrap_or(0xFF),
+                            u8::try_from(ending).unwrap_or(0xFF),
+                        ),
+                        None => (0xFE, 0xFE),
+                    };
+                    dst.put_u8(nsig_array);
+                    dst.put_u8(nsig_ending);
+                    dst.put_u8(match report.extracted.sig_function_pattern {
+                        _ if !report.extracted.has_sig_function => 0xFF,
+                        _ if report.extracted.full_player => 0xFD,
+                        Some(x) => u8::try_from(x).unwrap_or(0xFF),
+                        None => 0xFE,
+                    });
+                    dst.put_u64(report.extracted.signature_timestamp);
+                    dst.put_u8(match self_test_error.is_empty() {
+                        true => 0xFF,
+                        false => 0x00,
+                    });
+                    dst.put_u16(u16::try_from(self_test_error.len()).unwrap());
+                    dst.put_slice(self_test_error.as_bytes());
+                    dst.put_u16(u16::try_from(unresolved.len()).unwrap());
+                    dst.put_slice(unresolved.as_bytes());
+                }
+                (status, _) => {
+                    dst.put_u32(4);
+                    dst.put_u16(0x0000);
+                    dst.put_u16(status.err().map_or(0x0000, |x| x.code()));
+                }
+            },
             _ => {}
         }
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use tokio_util::bytes::BytesMut;
+
+    use super::*;
+
+    #[test]
+    fn long_signature_is_truncated() {
+        // a multi-byte character straddles the 16-bit size limit
+        let signature = format!("{}é", "a".repeat(usize::from(u16::MAX) - 1));
+        let mut dst = BytesMut::new();
+        OpcodeDecoder {}
+            .encode(
+                OpcodeResponse {
+                    opcode: JobOpcode::DecryptNSignature,
+                    request_id: 1,
+                    signature,
+                    ..Default::default()
+                },
+                &mut dst,
+            )
+            .unwrap();
+        let size = u16::from_be_bytes([dst[8], dst[9]]);
+        assert_eq!(usize::from(size), usize::from(u16::MAX) - 1);
+        assert_eq!(u32::from_be_bytes(dst[4..8].try_into().unwrap()), 2 + u32::from(size));
+        assert_eq!(dst.len(), 10 + usize::from(size));
+    }
+
+    fn player_status(opcode: JobOpcode) -> BytesMut {
+        let mut dst = BytesMut::new();
+        OpcodeDecoder {}
+            .encode(
+                OpcodeResponse {
+                    opcode,
+                    request_id: 1,
+                    has_player: 0xFF,
+                    player_id: 0x1234ABCD,
+                    next_update: 1700000000,
+                    player_url: "https://www.youtube.com/s/player/1234abcd/base.js".to_string(),
+                    discovery_source: "watch".to_string(),
+                    pinned: true,
+                    ..Default::default()
+                },
+                &mut dst,
+            )
+            .unwrap();
+        dst
+    }
+
+    #[test]
+    fn player_status_keeps_its_size() {
+        let dst = player_status(JobOpcode::PlayerStatus);
+        assert_eq!(&dst[..], &[0, 0, 0, 1, 0, 0, 0, 5, 0xFF, 0x12, 0x34, 0xAB, 0xCD]);
+    }
+
+    #[test]
+    fn player_details_extend_player_status() {
+        let status = player_status(JobOpcode::PlayerStatus);
+        let details = player_status(JobOpcode::PlayerDetails);
+        assert_eq!(&details[8..13], &status[8..13]);
+        assert_eq!(
+            u32::from_be_bytes(details[4..8].try_into().unwrap()) as usize,
+            details.len() - 8
+        );
+        assert_eq!(details[details.len() - 2..], [0x01, 0x00]);
+    }
+
+    fn decode(frame: &[u8]) -> Result<Option<Opcode>, std::io::Error> {
+        OpcodeDecoder {}.decode(&mut BytesMut::from(frame))
+    }
+
+    fn string(x: &str) -> Vec<u8> {
+        let mut bytes = u16::try_from(x.len()).unwrap().to_be_bytes().to_vec();
+        bytes.extend_from_slice(x.as_bytes());
+        bytes
+    }
+
+    fn long_string(x: &str) -> Vec<u8> {
+        let mut bytes = u32::try_from(x.len()).unwrap().to_be_bytes().to_vec();
+        bytes.extend_from_slice(x.as_bytes());
+        bytes
+    }
+
+    fn override_frame() -> Vec<u8> {
+        let mut frame = vec![0x0D, 0, 0, 0, 7];
+        frame.extend(string("tv"));
+        frame.extend(long_string("function decrypt_nsig(a){return a}"));
+        frame.extend(string("sig"));
+        frame.extend(long_string("function sig(a){return a}"));
+        frame.extend(19876u64.to_be_bytes());
+        frame
+    }
+
+    #[test]
+    fn read_string_waits_for_whole_string() {
+        let bytes = string("abc");
+        assert_eq!(read_string(&bytes, 0).unwrap(), Some(("abc".to_string(), 5)));
+        for length in 0..bytes.len() {
+            assert!(read_string(&bytes[..length], 0).unwrap().is_none());
+        }
+        assert!(read_string(&[0, 2, 0xC3, 0x28], 0).is_err());
+    }
+
+    #[test]
+    fn decodes_variant_request() {
+        let mut frame = vec![0x06, 0, 0, 0, 42];
+        frame.extend(string("tv"));
+        frame.extend(string("abcd"));
+        let mut src = BytesMut::from(&frame[..]);
+        src.extend_from_slice(&[0x00, 0, 0, 0, 43]);
+        let opcode = OpcodeDecoder {}.decode(&mut src).unwrap().unwrap();
+        assert!(matches!(opcode.opcode, JobOpcode::DecryptNSignatureVariant));
+        assert_eq!(opcode.request_id, 42);
+        assert_eq!(opcode.variant.as_deref(), Some("tv"));
+        assert_eq!(opcode.signature, "abcd");
+        // only the request is consumed
+        assert_eq!(&src[..], &[0x00, 0, 0, 0, 43]);
+    }
+
+    fn assert_waits_for_whole_frame(frame: &[u8]) {
+        for length in 0..frame.len() {
+            assert!(decode(&frame[..length]).unwrap().is_none(), "length {}", length);
+        }
+        assert!(decode(frame).unwrap().is_some());
+    }
+
+    #[test]
+    fn truncated_frames_wait_for_more_data() {
+        let mut signature = vec![0x01, 0, 0, 0, 1];
+        signature.extend(string("abcd"));
+        assert_waits_for_whole_frame(&signature);
+        let mut variant = vec![0x07, 0, 0, 0, 1];
+        variant.extend(string("tv"));
+        variant.extend(string("abcd"));
+        assert_waits_for_whole_frame(&variant);
+    }
+
+    #[test]
+    fn truncated_override_waits_for_more_data() {
+        assert_waits_for_whole_frame(&override_frame());
+    }
+
+    #[test]
+    fn decodes_function_override() {
+        let opcode = decode(&override_frame()).unwrap().unwrap();
+        let function_override = opcode.function_override.unwrap();
+        assert_eq!(opcode.variant.as_deref(), Some("tv"));
+        assert_eq!(function_override.sig_function_name, "sig");
+        assert_eq!(function_override.sig_function_code, "function sig(a){return a}");
+        assert_eq!(function_override.signature_timestamp, 19876);
+    }
+
+    #[test]
+    fn oversized_override_is_rejected() {
+        let mut frame = vec![0x0D, 0, 0, 0, 7];
+        frame.extend(string(""));
+        let size = u32::try_from(MAX_OVERRIDE_CODE_SIZE + 1).unwrap();
+        frame.extend(size.to_be_bytes());
+        let error = decode(&frame).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidData);
+    }
+
+    #[test]
+    fn unknown_opcode_is_rejected() {
+        let error = decode(&[0xFF, 0, 0, 0, 1]).err().unwrap();
+        assert_eq!(error.kind(), ErrorKind::InvalidInput);
+    }
+}
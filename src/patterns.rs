@@ -0,0 +1,194 @@
+use std::{fs, path::Path};
+use regex::Regex;
+use serde::Deserialize;
+
+use crate::{
+    config::{Config, ConfigError},
+    consts::{
+        NSIG_FUNCTION_ARRAYS, NSIG_FUNCTION_ENDINGS, REGEX_HELPER_OBJ_NAME,
+        REGEX_SIGNATURE_FUNCTION_PATTERNS,
+    },
+    fixups::{builtin_rules, fixup_rules, FixupRule},
+};
+
+/// Contents of the file given as `patterns_file`, missing lists keep the
+/// compiled-in patterns
+#[derive(Default, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct PatternsFile {
+    nsig_function_arrays: Option<Vec<String>>,
+    nsig_function_endings: Option<Vec<String>>,
+    signature_function_patterns: Option<Vec<String>>,
+    helper_object_name: Option<String>,
+}
+
+/// The regexes used to extract the functions from the player JS
+pub struct Patterns {
+    /// Find the nsig function, in `nfunc`, or the array holding it, in `nfunc` and `idx`
+    pub nsig_function_arrays: Vec<String>,
+    /// Appended to the nsig function name, capture the function after `function`
+    pub nsig_function_endings: Vec<String>,
+    /// Capture the sig function name, `GLOBAL_VAR_NAME` is replaced by the
+    /// name of the global array of the player
+    pub signature_function_patterns: Vec<String>,
+    /// Captures the name of the helper object in the sig function
+    pub helper_object_name: Regex,
+    /// Rewrites of the extracted code, from `fixups` in the configuration
+    pub fixups: Vec<FixupRule>,
+}
+
+impl Default for Patterns {
+    fn default() -> Self {
+        let to_vec = |x: &[&str]| x.iter().map(|x| x.to_string()).collect();
+        Patterns {
+            nsig_function_arrays: to_vec(NSIG_FUNCTION_ARRAYS),
+            nsig_function_endings: to_vec(NSIG_FUNCTION_ENDINGS),
+            signature_function_patterns: to_vec(REGEX_SIGNATURE_FUNCTION_PATTERNS),
+            helper_object_name: Regex::clone(REGEX_HELPER_OBJ_NAME),
+            fixups: builtin_rules(),
+        }
+    }
+}
+
+fn check(name: &str, pattern: &str, groups: &[&str]) -> Result<Regex, ConfigError> {
+    let regex = Regex::new(pattern)
+        .map_err(|x| ConfigError::Invalid(format!("{} pattern '{}': {}", name, pattern, x)))?;
+    for group in groups {
+        if !regex.capture_names().any(|x| x == Some(group)) {
+            return Err(ConfigError::Invalid(format!(
+                "{} pattern '{}' has no group named '{}'",
+                name, pattern, group
+            )));
+        }
+    }
+    if regex.captures_len() < 2 {
+        return Err(ConfigError::Invalid(format!(
+            "{} pattern '{}' has no capture group",
+            name, pattern
+        )));
+    }
+    Ok(regex)
+}
+
+impl Patterns {
+    /// The patterns of `patterns_file`, or the compiled-in ones if it is not
+    /// set, with the configured fixups
+    pub fn from_config(config: &Config) -> Result<Patterns, ConfigError> {
+        let mut patterns = match &config.patterns_file {
+            Some(path) => Patterns::load(path)?,
+            None => Patterns::default(),
+        };
+        patterns.fixups = fixup_rules(&config.fixups)?;
+        Ok(patterns)
+    }
+
+    fn load<P: AsRef<Path>>(path: P) -> Result<Patterns, ConfigError> {
+        let contents = fs::read_to_string(path).map_err(ConfigError::Io)?;
+        let file: PatternsFile = toml::from_str(&contents).map_err(ConfigError::Parse)?;
+        let defaults = Patterns::default();
+
+        let patterns = Patterns {
+            nsig_function_arrays: file
+                .nsig_function_arrays
+                .unwrap_or(defaults.nsig_function_arrays),
+            nsig_function_endings: file
+                .nsig_function_endings
+                .unwrap_or(defaults.nsig_function_endings),
+            signature_function_patterns: file
+                .signature_function_patterns
+                .unwrap_or(defaults.signature_function_patterns),
+            helper_object_name: match file.helper_object_name {
+                Some(x) => check("helper_object_name", &x, &[])?,
+                None => defaults.helper_object_name,
+            },
+            fixups: defaults.fixups,
+        };
+        for pattern in &patterns.nsig_function_arrays {
+            check("nsig_function_arrays", pattern, &["nfunc"])?;
+        }
+        for pattern in &patterns.nsig_function_endings {
+            // endings are only complete regexes behind a function name
+            check("nsig_function_endings", &format!("(?ms)name{}", pattern), &[])?;
+        }
+        for pattern in &patterns.signature_function_patterns {
+            check(
+                "signature_function_patterns",
+                &pattern.replace("GLOBAL_VAR_NAME", "name"),
+                &[],
+            )?;
+        }
+        Ok(patterns)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn load_str(name: &str, contents: &str) -> Result<Patterns, ConfigError> {
+        let path = std::env::temp_dir().join(format!(
+            "inv_sig_helper_patterns_{}_{}.toml",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, contents).unwrap();
+        let patterns = Patterns::load(&path);
+        fs::remove_file(&path).unwrap();
+        patterns
+    }
+
+    fn invalid_message(result: Result<Patterns, ConfigError>) -> String {
+        match result {
+            Err(ConfigError::Invalid(x)) => x,
+            Err(x) => panic!("unexpected error: {}", x),
+            Ok(_) => panic!("patterns were accepted"),
+        }
+    }
+
+    #[test]
+    fn missing_lists_keep_defaults() {
+        let patterns = load_str(
+            "partial",
+            r#"nsig_function_arrays = ['(?P<nfunc>[a-zA-Z0-9$]+)\[(?P<idx>\d+)\]\(b\)']"#,
+        )
+        .unwrap();
+        assert_eq!(patterns.nsig_function_arrays.len(), 1);
+        assert_eq!(patterns.nsig_function_endings.len(), NSIG_FUNCTION_ENDINGS.len());
+        assert_eq!(
+            patterns.signature_function_patterns.len(),
+            REGEX_SIGNATURE_FUNCTION_PATTERNS.len()
+        );
+    }
+
+    #[test]
+    fn nsig_array_needs_nfunc_group() {
+        let message = invalid_message(load_str(
+            "nfunc",
+            r#"nsig_function_arrays = ['([a-z]+)\(b\)']"#,
+        ));
+        assert!(message.contains("no group named 'nfunc'"), "{}", message);
+    }
+
+    #[test]
+    fn patterns_need_capture_group() {
+        let message = invalid_message(load_str(
+            "capture",
+            r#"signature_function_patterns = ['GLOBAL_VAR_NAME\.split']"#,
+        ));
+        assert!(message.contains("has no capture group"), "{}", message);
+    }
+
+    #[test]
+    fn invalid_regex_is_rejected() {
+        let message = invalid_message(load_str("regex", r#"helper_object_name = '(['"#));
+        assert!(message.starts_with("helper_object_name pattern"), "{}", message);
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        assert!(matches!(
+            load_str("unknown", "nsig_patterns = []"),
+            Err(ConfigError::Parse(_))
+        ));
+    }
+}
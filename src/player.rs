@@ -1,23 +1,215 @@
-use std::{sync::Arc, time::SystemTime};
+use std::{
+    collections::BTreeSet,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, Instant, SystemTime},
+};
 use log::{debug, error, info, warn};
+use futures::future::{BoxFuture, FutureExt, Shared};
 use regex::Regex;
+use tokio::sync::Mutex;
 
 use crate::{
+    ast::{parse_player, Declarations},
+    cache::{save_player, CachedPlayer},
+    fixups::{apply_fixups, fixed_code, FixupTarget, GlobalVar},
     consts::{
-        NSIG_FUNCTION_ARRAYS, NSIG_FUNCTION_ENDINGS, NSIG_FUNCTION_NAME, REGEX_HELPER_OBJ_NAME,
-        REGEX_PLAYER_ID, REGEX_SIGNATURE_FUNCTION_PATTERNS, REGEX_SIGNATURE_TIMESTAMP, TEST_YOUTUBE_VIDEO,
+        DEFAULT_PLAYER_PATH, FULL_PLAYER_PRELUDE, FULL_PLAYER_SIG_FUNCTION_NAME,
+        MAX_NSIG_CANDIDATES, NSIG_CANDIDATE_SIZES, NSIG_FUNCTION_NAME, REGEX_PLAYER_ID, REGEX_PLAYER_JS_URL,
+        REGEX_PLAYER_URL_PARTS, REGEX_SIGNATURE_TIMESTAMP, YOUTUBE_URL,
     },
-    jobs::GlobalState,
+    config::Config,
+    jobs::{FunctionOverride, GlobalState, PlayerInfo},
+    patterns::Patterns,
+    selftest::{best_nsig_candidate, self_test, self_test_functions, SelfTestError},
+    source::PlayerSource,
 };
 
 // TODO: too lazy to make proper debugging print
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum FetchUpdateStatus {
     CannotFetchTestVideo,
     CannotMatchPlayerID,
+    CannotBuildPlayerURL,
     CannotFetchPlayerJS,
     NsigRegexCompileFailed,
     PlayerAlreadyUpdated,
+    UpstreamCooldown,
+    UpdateAborted,
+    NsigArrayNotFound,
+    NsigArrayInvalid,
+    NsigFunctionNotFound,
+    SigFunctionBodyNotFound,
+    SigHelperObjectNotFound,
+    SignatureTimestampNotFound,
+    SelfTestFailed,
+    PlayerPinned,
+    AdminNotAllowed,
+    NoPreviousPlayer,
+    UnknownVariant,
+    NoOverride,
+    PatternsInvalid,
+}
+
+impl FetchUpdateStatus {
+    /// Code sent to clients in the `FORCE_UPDATE` response
+    pub fn code(&self) -> u16 {
+        match self {
+            Self::CannotFetchTestVideo => 0x01,
+            Self::CannotMatchPlayerID => 0x02,
+            Self::CannotBuildPlayerURL => 0x03,
+            Self::CannotFetchPlayerJS => 0x04,
+            Self::NsigRegexCompileFailed => 0x05,
+            Self::PlayerAlreadyUpdated => 0x06,
+            Self::UpstreamCooldown => 0x07,
+            Self::UpdateAborted => 0x08,
+            Self::NsigArrayNotFound => 0x09,
+            Self::NsigArrayInvalid => 0x0A,
+            Self::NsigFunctionNotFound => 0x0B,
+            Self::SigFunctionBodyNotFound => 0x0C,
+            Self::SigHelperObjectNotFound => 0x0D,
+            Self::SignatureTimestampNotFound => 0x0E,
+            Self::SelfTestFailed => 0x0F,
+            Self::PlayerPinned => 0x10,
+            Self::AdminNotAllowed => 0x11,
+            Self::NoPreviousPlayer => 0x12,
+            Self::UnknownVariant => 0x13,
+            Self::NoOverride => 0x14,
+            Self::PatternsInvalid => 0x15,
+        }
+    }
+}
+
+pub type SharedUpdate = Shared<BoxFuture<'static, Result<(), FetchUpdateStatus>>>;
+
+/// Settings shared by the updates of all variants
+struct UpdateContext {
+    source: PlayerSource,
+    cache_dir: Option<PathBuf>,
+    patterns: Arc<Patterns>,
+    fallbacks: Fallbacks,
+}
+
+impl UpdateContext {
+    async fn new(state: &GlobalState) -> UpdateContext {
+        let config = state.config.read().await;
+        let cache_dir = config.cache_dir.as_ref().map(PathBuf::from);
+        let fallbacks = Fallbacks::from_config(&config);
+        drop(config);
+        UpdateContext {
+            source: PlayerSource::from_state(state).await,
+            cache_dir,
+            patterns: state.patterns.read().await.clone(),
+            fallbacks,
+        }
+    }
+}
+
+/// What is tried when the functions of a player cannot be extracted
+#[derive(Clone, Copy, Default)]
+struct Fallbacks {
+    /// Find the nsig function by running the functions of the player
+    nsig_search: bool,
+    /// Run the whole player when the extracted functions fail
+    full_player: bool,
+}
+
+impl Fallbacks {
+    fn from_config(config: &Config) -> Fallbacks {
+        Fallbacks {
+            nsig_search: config.nsig_search,
+            full_player: config.full_player_fallback,
+        }
+    }
+}
+
+/// URL of the player JS as found on a YouTube page
+pub struct PlayerUrl {
+    pub url: String,
+    pub id: String,
+    /// The discovery strategy that found the URL
+    pub source: String,
+    /// Everything up to and including `/s/player/{id}/`, if the URL has that layout
+    prefix: Option<String>,
+}
+
+impl PlayerUrl {
+    fn parse(url: &str) -> PlayerUrl {
+        let mut url = url.replace("\\/", "/");
+        if url.starts_with("//") {
+            url = format!("https:{}", url);
+        } else if url.starts_with('/') {
+            url = format!("{}{}", YOUTUBE_URL, url);
+        }
+        match REGEX_PLAYER_URL_PARTS.captures(&url) {
+            Some(caps) => PlayerUrl {
+                id: caps[2].to_string(),
+                source: String::new(),
+                prefix: Some(caps[1].to_string()),
+                url,
+            },
+            None => PlayerUrl {
+                id: String::new(),
+                source: String::new(),
+                prefix: None,
+                url,
+            },
+        }
+    }
+
+    pub fn from_id(id: &str) -> PlayerUrl {
+        let prefix = format!("{}/s/player/{}/", YOUTUBE_URL, id);
+        PlayerUrl {
+            url: format!("{}{}", prefix, DEFAULT_PLAYER_PATH),
+            id: id.to_string(),
+            source: String::new(),
+            prefix: Some(prefix),
+        }
+    }
+
+    /// A player JS on the local filesystem, the id is taken from its directory
+    pub fn local(path: &Path) -> PlayerUrl {
+        let id = path
+            .parent()
+            .and_then(|x| x.file_name())
+            .map(|x| x.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        PlayerUrl {
+            url: path.to_string_lossy().into_owned(),
+            id,
+            source: "file".to_string(),
+            prefix: None,
+        }
+    }
+
+    /// Finds the player URL in a YouTube page, falling back to just the player id
+    pub fn discover(page: &str) -> Option<PlayerUrl> {
+        if let Some(caps) = REGEX_PLAYER_JS_URL.captures(page) {
+            return Some(PlayerUrl::parse(&caps[1]));
+        }
+        // the iframe API only has the id, in an escaped URL
+        let unescaped = page.replace("\\/", "/");
+        let caps = REGEX_PLAYER_ID.captures(&unescaped)?;
+        Some(PlayerUrl::from_id(&caps[1]))
+    }
+
+    /// The player id as shown by `PLAYER_STATUS`, 0 if it is not 8 hex digits
+    pub fn numeric_id(&self) -> u32 {
+        match self.id.len() {
+            8 => u32::from_str_radix(&self.id, 16).unwrap_or(0),
+            _ => 0,
+        }
+    }
+
+    /// URL of the player JS at `path` below `/s/player/{id}/`, or the URL as
+    /// found if `path` is `None`
+    pub fn with_path(&self, path: Option<&str>) -> Option<String> {
+        match (path, &self.prefix) {
+            (None, _) => Some(self.url.clone()),
+            (Some(path), Some(prefix)) => Some(format!("{}{}", prefix, path)),
+            (Some(_), None) => None,
+        }
+    }
 }
 
 fn extract_player_js_global_var(jscode: &str) -> Option<(String, String, String)> {
@@ -44,177 +236,168 @@ fn extract_player_js_global_var(jscode: &str) -> Option<(String, String, String)
     }
 }
 
-fn fixup_nsig_jscode(jscode: &str, player_javascript: &str) -> String {
-    // First try to extract any global variable
-    let mut result = jscode.to_string();
-    
-    // Extract the original parameter name from the input JavaScript code
-    let param_regex = Regex::new(r"function\s+[a-zA-Z0-9_$]+\s*\(([a-zA-Z0-9_$]+)\)").unwrap();
-    let param_name = param_regex.captures(jscode)
-        .and_then(|caps| caps.get(1))
-        .map(|m| m.as_str())
-        .unwrap_or("a"); // fallback to 'a' if we can't find the original parameter
-    
-    let fixup_re = if let Some((global_var, varname, _)) = extract_player_js_global_var(player_javascript) {
-        debug!("global_var: {}", global_var);
-        debug!("varname: {}", varname);
-        debug!("jscode: {}", jscode);
-
-        info!("Prepending n function code with global array variable '{}'", varname);
-        result = format!("function decrypt_nsig({}){{{}; {}", param_name, global_var, jscode.replace(&format!("function decrypt_nsig({}){{", param_name), ""));
-
-        // Escape special regex characters in the variable name
-        let escaped_varname = varname.replace("$", "\\$");
-        Regex::new(&format!(r#";\s*if\s*\(\s*typeof\s+[a-zA-Z0-9_$]+\s*===?\s*(?:"undefined"|'undefined'|{}\[\d+\])\s*\)\s*return\s+\w+;"#, escaped_varname)).unwrap()
-    } else {
-        info!("No global array variable found in player JS");
-        Regex::new(r#";\s*if\s*\(\s*typeof\s+[a-zA-Z0-9_$]+\s*===?\s*"undefined"\s*\)\s*return\s+\w+;"#).unwrap()
-    };
-
-    // Now handle the conditional return statement cleanup
-    // info!("jscode: {}", result);
-
-    // Replace the matched pattern with just ";"
-    if fixup_re.is_match(&result) {
-        info!("Fixing up nsig_func_body");
-        result = fixup_re.replace_all(&result, ";").to_string();
-        // info!("result: {}", result);
-    } else {
-        info!("nsig_func returned with no fixup");
-    }
-    result
+/// The functions and values extracted from a player JS
+pub struct ExtractedPlayer {
+    pub nsig_function_code: String,
+    pub sig_function_code: String,
+    pub sig_function_name: String,
+    pub signature_timestamp: u64,
+    /// Indexes of the `nsig_function_arrays` and `nsig_function_endings`
+    /// patterns that matched, `None` if the parser found the nsig function
+    pub nsig_patterns: Option<(usize, usize)>,
+    /// Index of the `signature_function_patterns` pattern that matched, `None`
+    /// if the parser found the sig function or there is none
+    pub sig_function_pattern: Option<usize>,
+    /// `false` if the player has no sig function and `sig_function_code` is a placeholder
+    pub has_sig_function: bool,
+    /// Whether the nsig function was found by running the functions of the player
+    pub nsig_searched: bool,
+    /// Names the functions use that neither the player nor the runtime declares
+    pub unresolved: Vec<String>,
+    /// Whether the functions are exported from the whole player JS, which
+    /// is the code of both
+    pub full_player: bool,
 }
 
-pub async fn fetch_update(state: Arc<GlobalState>) -> Result<(), FetchUpdateStatus> {
-    let global_state = state.clone();
-    let response = match reqwest::get(TEST_YOUTUBE_VIDEO).await {
-        Ok(req) => req.text().await.unwrap(),
-        Err(x) => {
-            error!("Could not fetch the test video: {}", x);
-            return Err(FetchUpdateStatus::CannotFetchTestVideo);
-        }
-    };
-
-    let player_id_str = match REGEX_PLAYER_ID.captures(&response).unwrap().get(1) {
-        Some(result) => result.as_str(),
-        None => return Err(FetchUpdateStatus::CannotMatchPlayerID),
-    };
-
-    let player_id: u32 = u32::from_str_radix(player_id_str, 16).unwrap();
-
-    let mut current_player_info = global_state.player_info.lock().await;
-    let current_player_id = current_player_info.player_id;
-
-    if player_id == current_player_id {
-        current_player_info.last_update = SystemTime::now();
-        return Err(FetchUpdateStatus::PlayerAlreadyUpdated);
-    }
-    // release the mutex for other tasks
-    drop(current_player_info);
-    
-    // Download the player script
-    let player_js_url: String = format!(
-        "https://www.youtube.com/s/player/{:08x}/player_ias.vflset/en_US/base.js",
-        player_id
-    );
-    info!("Fetching player JS URL: {}", player_js_url);
-    let player_javascript = match reqwest::get(player_js_url).await {
-        Ok(req) => req.text().await.unwrap(),
-        Err(x) => {
-            error!("Could not fetch the player JS: {}", x);
-            return Err(FetchUpdateStatus::CannotFetchPlayerJS);
-        }
-    };
-
-    let mut nsig_function_array_opt = None;
+/// Returns the name of the nsig function and the index of the
+/// `nsig_function_arrays` pattern that found it
+fn find_nsig_function_name(
+    player_javascript: &str,
+    patterns: &Patterns,
+) -> Result<(String, usize), FetchUpdateStatus> {
     // Extract nsig function array code
-    for (index, nsig_function_array_str) in NSIG_FUNCTION_ARRAYS.iter().enumerate() {
-        let nsig_function_array_regex = Regex::new(&nsig_function_array_str).unwrap();
-        nsig_function_array_opt = match nsig_function_array_regex.captures(&player_javascript) {
-            None => {
-                warn!("nsig function array did not work: {}", nsig_function_array_str);
-                if index == NSIG_FUNCTION_ARRAYS.len() {
-                    error!("!!ERROR!! nsig function array unable to be extracted");
-                    return Err(FetchUpdateStatus::NsigRegexCompileFailed);
-                }
+    let mut nsig_function_array_opt = None;
+    for (index, nsig_function_array_str) in patterns.nsig_function_arrays.iter().enumerate() {
+        let nsig_function_array_regex = match Regex::new(nsig_function_array_str) {
+            Ok(x) => x,
+            Err(x) => {
+                warn!("nsig function array regex is invalid: {}", x);
                 continue;
             }
+        };
+        match nsig_function_array_regex.captures(player_javascript) {
+            None => warn!("nsig function array did not work: {}", nsig_function_array_str),
             Some(i) => {
-                Some(i)
+                nsig_function_array_opt = Some((index, i));
+                break;
             }
-        };
-        break;
+        }
     }
-
-    let nsig_function_array = nsig_function_array_opt.unwrap();
-    let nsig_array_name = nsig_function_array.name("nfunc").unwrap().as_str();
-    let nsig_array_value = nsig_function_array
-        .name("idx")
-        .unwrap()
-        .as_str()
-        .parse::<usize>()
-        .unwrap();
-
-    let mut nsig_array_context_regex: String = String::new();
-    nsig_array_context_regex += "var ";
-    nsig_array_context_regex += &nsig_array_name.replace("$", "\\$");
-    nsig_array_context_regex += "\\s*=\\s*\\[(.+?)][;,]";
-
-    let nsig_array_context = match Regex::new(&nsig_array_context_regex) {
-        Ok(x) => x,
-        Err(x) => {
-            error!("Error: nsig regex compilation failed: {}", x);
-            return Err(FetchUpdateStatus::NsigRegexCompileFailed);
+    let (nsig_array_pattern, nsig_function_array) = match nsig_function_array_opt {
+        Some(x) => x,
+        None => {
+            error!("!!ERROR!! nsig function array unable to be extracted");
+            return Err(FetchUpdateStatus::NsigArrayNotFound);
         }
     };
+    let nsig_array_name = nsig_function_array
+        .name("nfunc")
+        .ok_or(FetchUpdateStatus::NsigArrayNotFound)?
+        .as_str();
 
-    let array_content = nsig_array_context
-        .captures(&player_javascript)
-        .unwrap()
-        .get(1)
-        .unwrap()
-        .as_str()
-        .split(',');
+    let nsig_function_name = match nsig_function_array.name("idx") {
+        // the function is called directly instead of through an array
+        None => nsig_array_name.to_string(),
+        Some(idx) => {
+            let nsig_array_value = idx
+                .as_str()
+                .parse::<usize>()
+                .map_err(|_| FetchUpdateStatus::NsigArrayInvalid)?;
 
-    let array_values: Vec<&str> = array_content.collect();
+            let mut nsig_array_context_regex: String = String::new();
+            nsig_array_context_regex += "var ";
+            nsig_array_context_regex += &nsig_array_name.replace("$", "\\$");
+            nsig_array_context_regex += "\\s*=\\s*\\[(.+?)][;,]";
 
-    let nsig_function_name = array_values.get(nsig_array_value).unwrap();
+            let nsig_array_context = match Regex::new(&nsig_array_context_regex) {
+                Ok(x) => x,
+                Err(x) => {
+                    error!("Error: nsig regex compilation failed: {}", x);
+                    return Err(FetchUpdateStatus::NsigRegexCompileFailed);
+                }
+            };
 
-    let mut nsig_function_code = String::new();
-    nsig_function_code += "function ";
-    nsig_function_code += NSIG_FUNCTION_NAME;
+            let array_content = match nsig_array_context
+                .captures(player_javascript)
+                .and_then(|x| x.get(1))
+            {
+                Some(x) => x.as_str(),
+                None => {
+                    error!("nsig function array {} not found", nsig_array_name);
+                    return Err(FetchUpdateStatus::NsigArrayInvalid);
+                }
+            };
+            let array_values: Vec<&str> = array_content.split(',').collect();
+            match array_values.get(nsig_array_value) {
+                Some(x) => x.to_string(),
+                None => {
+                    error!(
+                        "nsig function array {} has no index {}",
+                        nsig_array_name, nsig_array_value
+                    );
+                    return Err(FetchUpdateStatus::NsigArrayInvalid);
+                }
+            }
+        }
+    };
 
     debug!("nsig function name: {}", nsig_function_name);
+    Ok((nsig_function_name, nsig_array_pattern))
+}
+
+/// Returns the nsig function code and the indexes of the patterns that found it
+fn extract_nsig_function(
+    player_javascript: &str,
+    patterns: &Patterns,
+) -> Result<(String, usize, usize), FetchUpdateStatus> {
+    let (nsig_function_name, nsig_array_pattern) =
+        find_nsig_function_name(player_javascript, patterns)?;
 
     // Extract nsig function code
-    for (index, ending) in NSIG_FUNCTION_ENDINGS.iter().enumerate() {
+    for (nsig_ending_pattern, ending) in patterns.nsig_function_endings.iter().enumerate() {
         let mut nsig_function_code_regex_str: String = String::new();
         nsig_function_code_regex_str += "(?ms)";
         nsig_function_code_regex_str += &nsig_function_name.replace("$", "\\$");
         nsig_function_code_regex_str += ending;
 
-        let nsig_function_code_regex = Regex::new(&nsig_function_code_regex_str).unwrap();
-        nsig_function_code += match nsig_function_code_regex.captures(&player_javascript) {
+        let nsig_function_code_regex = match Regex::new(&nsig_function_code_regex_str) {
+            Ok(x) => x,
+            Err(x) => {
+                warn!("nsig function ending regex is invalid: {}", x);
+                continue;
+            }
+        };
+        let nsig_function_body = match nsig_function_code_regex
+            .captures(player_javascript)
+            .and_then(|x| x.get(1))
+        {
             None => {
                 warn!("nsig function ending did not work: {}", ending);
-                if index == NSIG_FUNCTION_ENDINGS.len() {
-                    error!("!!ERROR!! nsig function unable to be extracted");
-                    return Err(FetchUpdateStatus::NsigRegexCompileFailed);
-                }
-
                 continue;
             }
             Some(i) => {
                 debug!("nsig function ending worked: {}", ending);
-                i.get(1).unwrap().as_str()
+                i.as_str()
             }
         };
-        nsig_function_code = fixup_nsig_jscode(&nsig_function_code, &player_javascript);
+        let mut nsig_function_code = String::new();
+        nsig_function_code += "function ";
+        nsig_function_code += NSIG_FUNCTION_NAME;
+        nsig_function_code += nsig_function_body;
         debug!("got nsig fn code: {}", nsig_function_code);
-        break;
+        return Ok((nsig_function_code, nsig_array_pattern, nsig_ending_pattern));
     }
 
-    let (global_var, varname, _) = extract_player_js_global_var(&player_javascript).unwrap();
+    error!("!!ERROR!! nsig function unable to be extracted");
+    Err(FetchUpdateStatus::NsigFunctionNotFound)
+}
+
+/// Returns the name of the sig function and the index of the
+/// `signature_function_patterns` pattern that found it
+fn find_sig_function_name(player_javascript: &str, patterns: &Patterns) -> Option<(String, usize)> {
+    // not every player has a global variable
+    let (global_var, varname, _) =
+        extract_player_js_global_var(player_javascript).unwrap_or_default();
     if !global_var.is_empty() {
         debug!("Found global var for sig: {}", global_var);
         debug!("Found varname for sig: {}", varname);
@@ -224,9 +407,9 @@ pub async fn fetch_update(state: Arc<GlobalState>) -> Result<(), FetchUpdateStat
 
     // Extract signature function name
     let mut sig_function_name = String::new();
-    let mut found_sig_function = false;
-    
-    for sig_pattern in REGEX_SIGNATURE_FUNCTION_PATTERNS.iter() {
+    let mut sig_function_pattern = None;
+
+    for (index, sig_pattern) in patterns.signature_function_patterns.iter().enumerate() {
         let _sig_pattern = sig_pattern.replace("GLOBAL_VAR_NAME", &regex::escape(&varname));
 
         debug!("sig pattern: {}", _sig_pattern);
@@ -239,99 +422,996 @@ pub async fn fetch_update(state: Arc<GlobalState>) -> Result<(), FetchUpdateStat
             }
         };
 
-        if let Some(cap) = sig_regex.captures(&player_javascript) {
+        if let Some(cap) = sig_regex.captures(player_javascript) {
             if let Some(m) = cap.get(1) {
                 sig_function_name = m.as_str().to_string();
-                found_sig_function = true;
+                sig_function_pattern = Some(index);
                 break;
             }
         }
     }
 
-    if !found_sig_function {
-        sig_function_name = format!("sig_function_{}", SystemTime::now().duration_since(SystemTime::UNIX_EPOCH).unwrap().as_millis());
-        info!("No signature function found in player JS, using random name: {}", sig_function_name);
-    }
+    let sig_function_pattern = match sig_function_pattern {
+        Some(x) => x,
+        None => {
+            info!("No signature function found in player JS");
+            return None;
+        }
+    };
+
+    debug!("found sig function: {}", sig_function_name);
+    Some((sig_function_name, sig_function_pattern))
+}
+
+fn extract_sig_function(
+    player_javascript: &str,
+    patterns: &Patterns,
+) -> Result<Option<(String, String, usize)>, FetchUpdateStatus> {
+    let (sig_function_name, sig_function_pattern) =
+        match find_sig_function_name(player_javascript, patterns) {
+            Some(x) => x,
+            None => return Ok(None),
+        };
+
+    let mut sig_function_body_regex_str: String = String::new();
+    sig_function_body_regex_str += &sig_function_name.replace("$", "\\$");
+    sig_function_body_regex_str += "=function\\([a-zA-Z0-9_]+\\)\\{.+?\\}";
+
+    let sig_function_body_regex = Regex::new(&sig_function_body_regex_str)
+        .map_err(|_| FetchUpdateStatus::SigFunctionBodyNotFound)?;
+    let sig_function_body = match sig_function_body_regex.find(player_javascript) {
+        Some(x) => x.as_str(),
+        None => {
+            error!("Body of signature function {} not found", sig_function_name);
+            return Err(FetchUpdateStatus::SigFunctionBodyNotFound);
+        }
+    };
+
+    // Get the helper object
+    let helper_object_name = match patterns
+        .helper_object_name
+        .captures(sig_function_body)
+        .and_then(|x| x.get(1))
+    {
+        Some(x) => x.as_str(),
+        None => {
+            error!("No helper object used in signature function {}", sig_function_name);
+            return Err(FetchUpdateStatus::SigHelperObjectNotFound);
+        }
+    };
+
+    let mut helper_object_body_regex_str = String::new();
+    helper_object_body_regex_str += "(var ";
+    helper_object_body_regex_str += &helper_object_name.replace("$", "\\$");
+    helper_object_body_regex_str += "=\\{(?:.|\\n)+?\\}\\};)";
+
+    let helper_object_body_regex = Regex::new(&helper_object_body_regex_str)
+        .map_err(|_| FetchUpdateStatus::SigHelperObjectNotFound)?;
+    let helper_object_body = match helper_object_body_regex.find(player_javascript) {
+        Some(x) => x.as_str(),
+        None => {
+            error!("Helper object {} not found", helper_object_name);
+            return Err(FetchUpdateStatus::SigHelperObjectNotFound);
+        }
+    };
 
     let mut sig_code = String::new();
+    sig_code += "var ";
+    sig_code += &sig_function_name;
+    sig_code += ";";
+    sig_code += helper_object_body;
+    sig_code += sig_function_body;
 
-    // if sig_function_name is not empty, then we need to extract the function body
-    if found_sig_function {
-        debug!("found sig function: {}", sig_function_name);
-        
-        let mut sig_function_body_regex_str: String = String::new();
-        sig_function_body_regex_str += &sig_function_name.replace("$", "\\$");
-        sig_function_body_regex_str += "=function\\([a-zA-Z0-9_]+\\)\\{.+?\\}";
-
-        let sig_function_body_regex = Regex::new(&sig_function_body_regex_str).unwrap();
-
-        let sig_function_body = sig_function_body_regex
-            .captures(&player_javascript)
-            .unwrap()
-            .get(0)
-            .unwrap()
-            .as_str();
-
-        // Get the helper object
-        let helper_object_name = REGEX_HELPER_OBJ_NAME
-            .captures(sig_function_body)
-            .unwrap()
-            .get(1)
-            .unwrap()
-            .as_str();
-
-        let mut helper_object_body_regex_str = String::new();
-        helper_object_body_regex_str += "(var ";
-        helper_object_body_regex_str += &helper_object_name.replace("$", "\\$");
-        helper_object_body_regex_str += "=\\{(?:.|\\n)+?\\}\\};)";
-
-        let helper_object_body_regex = Regex::new(&helper_object_body_regex_str).unwrap();
-        let helper_object_body = helper_object_body_regex
-            .captures(&player_javascript)
-            .unwrap()
-            .get(0)
-            .unwrap()
-            .as_str();
-
-        sig_code += "var ";
-        sig_code += &sig_function_name;
-        sig_code += ";";
-
-        if !global_var.is_empty() {
-            sig_code += &global_var;
-            sig_code += ";";
-        }
-
-        sig_code += helper_object_body;
-        sig_code += sig_function_body;
-    } else {
-        // just return empty sig function code with random name
-        sig_code += "var ";
-        sig_code += &sig_function_name;
-        sig_code += ";";
-        info!("No signature function found in player JS, just returning empty sig function code with random name: {}", sig_function_name);
+    debug!("sig code: {}", sig_code);
+    Ok(Some((sig_function_name, sig_code, sig_function_pattern)))
+}
+
+/// Finds the nsig function by running the functions of the player that take
+/// one argument on a sample `n` value, returns the code declaring it as
+/// `decrypt_nsig`
+async fn search_nsig_function(
+    player_javascript: &str,
+    patterns: &Patterns,
+    declarations: &Declarations,
+    global_var: Option<&GlobalVar>,
+) -> Option<String> {
+    let functions = declarations.one_argument_functions(player_javascript, NSIG_CANDIDATE_SIZES.clone());
+    info!(
+        "Searching the nsig function among {} functions",
+        functions.len().min(MAX_NSIG_CANDIDATES)
+    );
+    let mut candidates: Vec<(String, String)> = Vec::new();
+    let mut runnable: Vec<String> = Vec::new();
+    for (name, function) in functions.into_iter().take(MAX_NSIG_CANDIDATES) {
+        let code = format!("function {}{};", NSIG_FUNCTION_NAME, function);
+        // the fixups and dependencies the code gets if it is picked, without
+        // logging them for every candidate
+        let (fixed, _) = fixed_code(
+            &patterns.fixups,
+            FixupTarget::Nsig,
+            NSIG_FUNCTION_NAME,
+            code.clone(),
+            global_var,
+        );
+        let dependencies = match declarations.resolve(player_javascript, &fixed) {
+            Ok(x) => x.code,
+            Err(_) => continue,
+        };
+        runnable.push(format!("{};{}", fixed, dependencies));
+        candidates.push((name, code));
     }
 
-    debug!("sig code: {}", sig_code);
+    match best_nsig_candidate(&runnable).await {
+        Ok(Some(x)) => {
+            let (name, code) = candidates.swap_remove(x);
+            info!("nsig search picked function {}", name);
+            Some(code)
+        }
+        Ok(None) => {
+            error!("nsig search found no function returning a plausible nsig value");
+            None
+        }
+        Err(x) => {
+            error!("nsig search failed: {}", x);
+            None
+        }
+    }
+}
 
-    // Get signature timestamp
-    let signature_timestamp: u64 = REGEX_SIGNATURE_TIMESTAMP
-        .captures(&player_javascript)
-        .unwrap()
-        .get(1)
-        .unwrap()
-        .as_str()
-        .parse()
-        .unwrap();
+/// Extracts everything needed to serve requests from a player JS, searching
+/// the nsig function if `nsig_search` is set and nothing else finds it
+pub async fn extract_player(
+    player_javascript: &str,
+    patterns: &Patterns,
+    nsig_search: bool,
+) -> Result<ExtractedPlayer, FetchUpdateStatus> {
+    // the patterns are the fallback for what the parser cannot find
+    let (parsed_nsig_function, parsed_sig_function, declarations) =
+        match parse_player(player_javascript) {
+            Ok(x) => (x.nsig_function_code, x.sig_function, Some(x.declarations)),
+            Err(x) => {
+                warn!("Could not parse the player JS, using the patterns: {}", x);
+                (None, None, None)
+            }
+        };
+    let global_var = match extract_player_js_global_var(player_javascript) {
+        Some((code, name, _)) => Some(GlobalVar { code, name }),
+        None => {
+            info!("No global array variable found in player JS");
+            None
+        }
+    };
+    let mut nsig_searched = false;
+    let (nsig_function_code, nsig_patterns) = match parsed_nsig_function {
+        Some(x) => (x, None),
+        None => {
+            info!("Parser did not find the nsig function, using the patterns");
+            match (extract_nsig_function(player_javascript, patterns), &declarations) {
+                (Ok((code, array, ending)), _) => (code, Some((array, ending))),
+                (Err(x), Some(declarations)) if nsig_search => {
+                    let code = search_nsig_function(
+                        player_javascript,
+                        patterns,
+                        declarations,
+                        global_var.as_ref(),
+                    )
+                    .await
+                    .ok_or(x)?;
+                    nsig_searched = true;
+                    (code, None)
+                }
+                (Err(x), _) => return Err(x),
+            }
+        }
+    };
+    let sig_function = match parsed_sig_function {
+        Some((name, code)) => Some((name, code, None)),
+        None => {
+            info!("Parser did not find the sig function, using the patterns");
+            extract_sig_function(player_javascript, patterns)?
+                .map(|(name, code, index)| (name, code, Some(index)))
+        }
+    };
+    let sig_function_pattern = sig_function.as_ref().and_then(|(_, _, x)| *x);
+    let has_sig_function = sig_function.is_some();
+
+    let nsig_function_code = apply_fixups(
+        &patterns.fixups,
+        FixupTarget::Nsig,
+        NSIG_FUNCTION_NAME,
+        nsig_function_code,
+        global_var.as_ref(),
+    );
+    debug!("nsig code after fixups: {}", nsig_function_code);
+    let mut unresolved = BTreeSet::new();
+    let nsig_function_code = match &declarations {
+        Some(x) => add_dependencies(x, player_javascript, "nsig", nsig_function_code, &mut unresolved),
+        None => nsig_function_code,
+    };
+    let (sig_function_name, sig_function_code) = match sig_function {
+        Some((name, code, _)) => {
+            let code = apply_fixups(
+                &patterns.fixups,
+                FixupTarget::Sig,
+                &name,
+                code,
+                global_var.as_ref(),
+            );
+            debug!("sig code after fixups: {}", code);
+            let code = match &declarations {
+                Some(x) => add_dependencies(x, player_javascript, "sig", code, &mut unresolved),
+                None => code,
+            };
+            (name, code)
+        }
+        None => sig_function_placeholder(),
+    };
+
+    Ok(ExtractedPlayer {
+        nsig_function_code,
+        sig_function_code,
+        sig_function_name,
+        signature_timestamp: signature_timestamp(player_javascript)?,
+        nsig_patterns,
+        sig_function_pattern,
+        has_sig_function,
+        nsig_searched,
+        unresolved: unresolved.into_iter().collect(),
+        full_player: false,
+    })
+}
+
+/// Name and code of a sig function for players that have none
+fn sig_function_placeholder() -> (String, String) {
+    // just return empty sig function code with random name
+    let sig_function_name = format!(
+        "sig_function_{}",
+        SystemTime::now()
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis()
+    );
+    let sig_function_code = format!("var {};", sig_function_name);
+    (sig_function_name, sig_function_code)
+}
+
+fn signature_timestamp(player_javascript: &str) -> Result<u64, FetchUpdateStatus> {
+    match REGEX_SIGNATURE_TIMESTAMP
+        .captures(player_javascript)
+        .and_then(|x| x.get(1))
+        .and_then(|x| x.as_str().parse().ok())
+    {
+        Some(x) => Ok(x),
+        None => {
+            error!("Signature timestamp not found in player JS");
+            Err(FetchUpdateStatus::SignatureTimestampNotFound)
+        }
+    }
+}
+
+/// Runs the whole player JS after a stub of the browser environment instead
+/// of extracting the functions. The parser inserts code exporting the nsig
+/// and sig functions, found by the parser or the patterns, into the player.
+fn extract_full_player(
+    player_javascript: &str,
+    patterns: &Patterns,
+) -> Result<ExtractedPlayer, FetchUpdateStatus> {
+    let parsed = match parse_player(player_javascript) {
+        Ok(x) => x,
+        Err(x) => {
+            error!("Cannot run a player JS that does not parse: {}", x);
+            return Err(FetchUpdateStatus::NsigFunctionNotFound);
+        }
+    };
+    let nsig_function_name = match parsed.nsig_function_name {
+        Some(x) => x,
+        None => find_nsig_function_name(player_javascript, patterns)?.0,
+    };
+    let sig_function_name = match parsed.sig_function {
+        Some((name, _)) => Some(name),
+        None => find_sig_function_name(player_javascript, patterns).map(|(name, _)| name),
+    };
+
+    let mut exports = vec![(NSIG_FUNCTION_NAME, nsig_function_name.as_str())];
+    if let Some(x) = &sig_function_name {
+        exports.push((FULL_PLAYER_SIG_FUNCTION_NAME, x.as_str()));
+    }
+    let hooked = match parsed.declarations.export(player_javascript, &exports) {
+        Ok(x) => x,
+        Err(x) => {
+            error!("Cannot export the functions of the player JS: {}", x);
+            return Err(FetchUpdateStatus::NsigFunctionNotFound);
+        }
+    };
+    let code = format!("{}{}", FULL_PLAYER_PRELUDE, hooked);
+    info!(
+        "Running the whole player JS, nsig function {}, sig function {}",
+        nsig_function_name,
+        sig_function_name.as_deref().unwrap_or("none")
+    );
+
+    let has_sig_function = sig_function_name.is_some();
+    let (sig_function_name, sig_function_code) = match sig_function_name {
+        Some(_) => (FULL_PLAYER_SIG_FUNCTION_NAME.to_string(), code.clone()),
+        None => sig_function_placeholder(),
+    };
+    Ok(ExtractedPlayer {
+        nsig_function_code: code,
+        sig_function_code,
+        sig_function_name,
+        signature_timestamp: signature_timestamp(player_javascript)?,
+        nsig_patterns: None,
+        sig_function_pattern: None,
+        has_sig_function,
+        nsig_searched: false,
+        unresolved: Vec::new(),
+        full_player: true,
+    })
+}
+
+/// Extracts the functions of a player JS and runs the self-test on them,
+/// running the whole player instead if that fallback is enabled and this
+/// fails. Returns the self-test error of the functions that failed it.
+///
+/// Parsing the player and running its code takes seconds and does not yield,
+/// so this runs on a blocking thread instead of holding up a worker thread.
+async fn extract_tested_player(
+    player_javascript: Arc<String>,
+    patterns: Arc<Patterns>,
+    fallbacks: Fallbacks,
+) -> Result<(ExtractedPlayer, Option<SelfTestError>), FetchUpdateStatus> {
+    let handle = tokio::runtime::Handle::current();
+    tokio::task::spawn_blocking(move || {
+        handle.block_on(extract_tested_player_blocking(&player_javascript, &patterns, fallbacks))
+    })
+    .await
+    .unwrap_or_else(|x| {
+        error!("Extracting the player failed: {}", x);
+        Err(FetchUpdateStatus::UpdateAborted)
+    })
+}
+
+async fn extract_tested_player_blocking(
+    player_javascript: &str,
+    patterns: &Patterns,
+    fallbacks: Fallbacks,
+) -> Result<(ExtractedPlayer, Option<SelfTestError>), FetchUpdateStatus> {
+    let extracted = match extract_player(player_javascript, patterns, fallbacks.nsig_search).await {
+        Ok(x) => match self_test(&x).await {
+            Ok(()) => return Ok((x, None)),
+            Err(error) => Ok((x, Some(error))),
+        },
+        Err(x) => Err(x),
+    };
+    if !fallbacks.full_player {
+        return extracted;
+    }
+    match &extracted {
+        Ok((_, Some(x))) => warn!("Extracted functions failed the self-test, running the whole player: {}", x),
+        Err(x) => warn!("Could not extract the functions ({:?}), running the whole player", x),
+        Ok((_, None)) => {}
+    }
+
+    let full_player = match extract_full_player(player_javascript, patterns) {
+        Ok(x) => x,
+        Err(_) => return extracted,
+    };
+    match self_test(&full_player).await {
+        Ok(()) => Ok((full_player, None)),
+        Err(error) => {
+            error!("Whole player failed the self-test: {}", error);
+            match extracted {
+                Ok(x) => Ok(x),
+                Err(_) => Ok((full_player, Some(error))),
+            }
+        }
+    }
+}
+
+/// Appends the player declarations `code` depends on, after the code so they
+/// can use what the fixups put in front of it
+fn add_dependencies(
+    declarations: &Declarations,
+    player_javascript: &str,
+    function: &str,
+    code: String,
+    unresolved: &mut BTreeSet<String>,
+) -> String {
+    let dependencies = match declarations.resolve(player_javascript, &code) {
+        Ok(x) => x,
+        Err(x) => {
+            warn!("Could not resolve the dependencies of the {} code: {}", function, x);
+            return code;
+        }
+    };
+    if !dependencies.unresolved.is_empty() {
+        let names: Vec<&str> = dependencies.unresolved.iter().map(|x| x.as_str()).collect();
+        warn!(
+            "{} code uses names the player does not declare: {}",
+            function,
+            names.join(", ")
+        );
+    }
+    unresolved.extend(dependencies.unresolved);
+    debug!("{} code dependencies: {}", function, dependencies.code);
+    match dependencies.code.is_empty() {
+        true => code,
+        false => format!("{};{}", code, dependencies.code),
+    }
+}
+
+/// Updates the player, or waits for the update that is already running and
+/// returns its result
+pub async fn fetch_update(state: Arc<GlobalState>) -> Result<(), FetchUpdateStatus> {
+    let mut update_in_flight = state.update_in_flight.lock().await;
+    let update = match &*update_in_flight {
+        Some(x) => {
+            debug!("Player update already in progress, waiting for it");
+            x.clone()
+        }
+        None => {
+            let task_state = state.clone();
+            // spawned so the update finishes even if every requester goes away
+            let handle = tokio::spawn(async move {
+                let result = fetch_update_uncoalesced(task_state.clone()).await;
+                *task_state.update_in_flight.lock().await = None;
+                result
+            });
+            let update = handle
+                .map(|x| x.unwrap_or(Err(FetchUpdateStatus::UpdateAborted)))
+                .boxed()
+                .shared();
+            *update_in_flight = Some(update.clone());
+            update
+        }
+    };
+    drop(update_in_flight);
+
+    update.await
+}
 
-    current_player_info = global_state.player_info.lock().await;
+/// What a dry-run update found for the default variant
+pub struct DryRunReport {
+    pub player_url: PlayerUrl,
+    pub extracted: ExtractedPlayer,
+    /// Why the self-test failed, `None` if it passed
+    pub self_test_error: Option<String>,
+}
+
+/// Downloads and extracts the current player of the default variant without
+/// activating it
+pub async fn dry_run_update(state: Arc<GlobalState>) -> Result<DryRunReport, FetchUpdateStatus> {
+    let context = UpdateContext::new(&state).await;
+    let player_url = context.source.discover(&state).await?;
+    info!(
+        "Dry run: found player URL {} (id {}) using {}",
+        player_url.url, player_url.id, player_url.source
+    );
+
+    let config = state.config.read().await;
+    let variant = state.players.read().await.default_variant.clone();
+    let player_path = match config.uses_discovered_url(&variant) {
+        true => None,
+        false => Some(config.player_path(&variant).unwrap_or_default()),
+    };
+    drop(config);
+    let player_js_url = context
+        .source
+        .player_js_url(&player_url, player_path.as_deref())
+        .ok_or(FetchUpdateStatus::CannotBuildPlayerURL)?;
+
+    let player_javascript = context.source.fetch_player_js(&player_js_url).await?;
+    let (extracted, self_test_error) = extract_tested_player(
+        Arc::new(player_javascript),
+        context.patterns.clone(),
+        context.fallbacks,
+    )
+    .await?;
+    let self_test_error = self_test_error.map(|x| x.to_string());
+    info!(
+        "Dry run of {}: signature timestamp {}, self-test {}",
+        player_js_url,
+        extracted.signature_timestamp,
+        self_test_error.as_deref().unwrap_or("passed")
+    );
+    Ok(DryRunReport {
+        player_url,
+        extracted,
+        self_test_error,
+    })
+}
+
+/// Replaces the functions of the player of `variant` with hand-written ones,
+/// until a new player is fetched or the override is cleared
+pub async fn override_player(
+    state: Arc<GlobalState>,
+    variant: Option<String>,
+    function_override: FunctionOverride,
+) -> Result<(), FetchUpdateStatus> {
+    let (variant, player) = state
+        .player(variant.as_deref())
+        .await
+        .ok_or(FetchUpdateStatus::UnknownVariant)?;
+
+    let player_info = player.lock().await;
+    let nsig_function_code = match function_override.nsig_function_code.is_empty() {
+        true => player_info.nsig_function_code.clone(),
+        false => function_override.nsig_function_code.clone(),
+    };
+    let (sig_function_name, sig_function_code) =
+        match function_override.sig_function_code.is_empty() {
+            true => (
+                player_info.sig_function_name.clone(),
+                player_info.sig_function_code.clone(),
+            ),
+            false => (
+                function_override.sig_function_name.clone(),
+                function_override.sig_function_code.clone(),
+            ),
+        };
+    drop(player_info);
+
+    // the placeholder of players without a sig function cannot be called
+    let sig_function = match sig_function_code == format!("var {};", sig_function_name) {
+        true => None,
+        false => Some((sig_function_name.as_str(), sig_function_code.as_str())),
+    };
+    if let Err(x) = self_test_functions(&nsig_function_code, sig_function).await {
+        error!("Override of player variant {} failed the self-test: {}", variant, x);
+        return Err(FetchUpdateStatus::SelfTestFailed);
+    }
+
+    player.lock().await.apply_override(function_override);
+    info!("Overrode the functions of player variant {}", variant);
+    Ok(())
+}
+
+/// Goes back to the extracted functions of the player of `variant`
+pub async fn clear_override(
+    state: Arc<GlobalState>,
+    variant: Option<String>,
+) -> Result<(), FetchUpdateStatus> {
+    let (variant, player) = state
+        .player(variant.as_deref())
+        .await
+        .ok_or(FetchUpdateStatus::UnknownVariant)?;
+    let cleared = player.lock().await.clear_override();
+    match cleared {
+        true => {
+            info!("Cleared the override of player variant {}", variant);
+            Ok(())
+        }
+        false => Err(FetchUpdateStatus::NoOverride),
+    }
+}
+
+/// Loads `patterns_file` again and extracts the functions of every variant
+/// from its player JS with the new patterns. Variants whose functions cannot
+/// be extracted or fail the self-test keep the current ones.
+pub async fn reload_patterns(state: Arc<GlobalState>) -> Result<(), FetchUpdateStatus> {
+    let config = state.config.read().await;
+    let patterns = match Patterns::from_config(&config) {
+        Ok(x) => x,
+        Err(x) => {
+            error!("Keeping the current patterns, {}", x);
+            return Err(FetchUpdateStatus::PatternsInvalid);
+        }
+    };
+    let cache_dir = config.cache_dir.as_ref().map(PathBuf::from);
+    let fallbacks = Fallbacks::from_config(&config);
+    drop(config);
+    let patterns = Arc::new(patterns);
+    *state.patterns.write().await = patterns.clone();
+    info!("Reloaded the extraction patterns");
+
+    let players: Vec<(String, Arc<Mutex<PlayerInfo>>)> = state
+        .players
+        .read()
+        .await
+        .variants
+        .iter()
+        .map(|(variant, player)| (variant.clone(), player.clone()))
+        .collect();
+    let mut status = Ok(());
+    for (variant, player) in players {
+        let player_js = player.lock().await.player_js.clone();
+        if player_js.is_empty() {
+            continue;
+        }
+        let result = match extract_tested_player(player_js.clone(), patterns.clone(), fallbacks).await {
+            Ok((extracted, None)) => Ok(extracted),
+            Ok((_, Some(x))) => {
+                error!("Functions of variant {} failed the self-test: {}", variant, x);
+                Err(FetchUpdateStatus::SelfTestFailed)
+            }
+            Err(x) => Err(x),
+        };
+        let extracted = match result {
+            Ok(x) => x,
+            Err(x) => {
+                error!("Keeping the current functions of variant {}", variant);
+                if status.is_ok() {
+                    status = Err(x);
+                }
+                continue;
+            }
+        };
+
+        let mut current_player_info = player.lock().await;
+        // an update replaced the player in the meantime
+        if !Arc::ptr_eq(&current_player_info.player_js, &player_js) {
+            continue;
+        }
+        current_player_info.set_functions(extracted);
+        // the cache keeps the extracted functions, not an override
+        let cached = CachedPlayer::from_player_info(
+            current_player_info
+                .overridden
+                .as_deref()
+                .unwrap_or(&current_player_info),
+        );
+        drop(current_player_info);
+        info!("Extracted the functions of variant {} again", variant);
+
+        if let Some(dir) = &cache_dir {
+            if let Err(x) = save_player(dir, &variant, &cached, &player_js).await {
+                warn!("Could not cache the player of variant {}: {}", variant, x);
+            }
+        }
+    }
+    status
+}
+
+/// Waits for the update that is running, if any
+async fn wait_for_update(state: &GlobalState) {
+    let running = state.update_in_flight.lock().await.clone();
+    if let Some(x) = running {
+        let _ = x.await;
+    }
+}
+
+/// Pins the player with `player_id`, or the active player if `None`, so that
+/// updates do not replace it until it is unpinned
+pub async fn pin_player(
+    state: Arc<GlobalState>,
+    player_id: Option<String>,
+) -> Result<(), FetchUpdateStatus> {
+    let was_pinned = std::mem::replace(&mut *state.pinned.lock().await, true);
+    // a running update could still replace the pinned player
+    wait_for_update(&state).await;
+
+    let player_id = match player_id {
+        Some(x) => x,
+        None => {
+            info!("Pinned the active player");
+            return Ok(());
+        }
+    };
+    let context = UpdateContext::new(&state).await;
+    let result = match context.source.player_url_for_id(&player_id) {
+        Some(player_url) => {
+            info!("Pinning player {} from {}", player_id, player_url.url);
+            update_players(&state, &context, &player_url).await
+        }
+        None => Err(FetchUpdateStatus::CannotMatchPlayerID),
+    };
+    match result {
+        Ok(()) | Err(FetchUpdateStatus::PlayerAlreadyUpdated) => {
+            info!("Pinned player {}", player_id);
+            Ok(())
+        }
+        Err(x) => {
+            error!("Could not pin player {}: {:?}", player_id, x);
+            *state.pinned.lock().await = was_pinned;
+            Err(x)
+        }
+    }
+}
+
+/// Makes the previously active player active again and pins it
+pub async fn roll_back_player(state: Arc<GlobalState>) -> Result<(), FetchUpdateStatus> {
+    let was_pinned = std::mem::replace(&mut *state.pinned.lock().await, true);
+    wait_for_update(&state).await;
+
+    let players = state.players.read().await;
+    let default_player = &players.variants[&players.default_variant];
+    if default_player.lock().await.previous.is_none() {
+        warn!("There is no previous player to roll back to");
+        *state.pinned.lock().await = was_pinned;
+        return Err(FetchUpdateStatus::NoPreviousPlayer);
+    }
+    let mut rolled_back = Vec::new();
+    for (variant, player) in &players.variants {
+        let mut player_info = player.lock().await;
+        if !player_info.roll_back() {
+            continue;
+        }
+        info!(
+            "Rolled back player variant {} to {}",
+            variant, player_info.player_url
+        );
+        rolled_back.push((
+            variant.clone(),
+            CachedPlayer::from_player_info(&player_info),
+            player_info.player_js.clone(),
+        ));
+    }
+    drop(players);
+
+    // a restart must not bring back the player that was rolled back, the
+    // players are written without holding their locks
+    let cache_dir = state.config.read().await.cache_dir.as_ref().map(PathBuf::from);
+    if let Some(dir) = &cache_dir {
+        for (variant, cached, player_js) in rolled_back {
+            if let Err(x) = save_player(dir, &variant, &cached, &player_js).await {
+                warn!("Could not cache the player of variant {}: {}", variant, x);
+            }
+        }
+    }
+    Ok(())
+}
+
+async fn fetch_update_uncoalesced(state: Arc<GlobalState>) -> Result<(), FetchUpdateStatus> {
+    let global_state = state.clone();
+
+    if *global_state.pinned.lock().await {
+        debug!("Player is pinned, not updating it");
+        return Err(FetchUpdateStatus::PlayerPinned);
+    }
+
+    let context = UpdateContext::new(&global_state).await;
+    let cooldown = match context.source.is_remote() {
+        true => global_state.config.read().await.upstream_cooldown(),
+        // local players can be edited and reloaded at any time
+        false => Duration::ZERO,
+    };
+
+    // answer from the current player instead of asking YouTube again
+    let mut last_upstream_fetch = global_state.last_upstream_fetch.lock().await;
+    if let Some(last_fetch) = *last_upstream_fetch {
+        if last_fetch.elapsed() < cooldown {
+            drop(last_upstream_fetch);
+            debug!(
+                "Player was fetched {} seconds ago, not fetching it again",
+                last_fetch.elapsed().as_secs()
+            );
+            // a failed fetch stays a failure until the cooldown is over
+            return match *global_state.last_upstream_result.lock().await {
+                Some(Ok(()) | Err(FetchUpdateStatus::PlayerAlreadyUpdated)) => {
+                    Err(FetchUpdateStatus::PlayerAlreadyUpdated)
+                }
+                _ => Err(FetchUpdateStatus::UpstreamCooldown),
+            };
+        }
+    }
+    *last_upstream_fetch = Some(Instant::now());
+    *global_state.last_upstream_result.lock().await = None;
+    drop(last_upstream_fetch);
+
+    let result = fetch_upstream(&global_state, &context).await;
+    *global_state.last_upstream_result.lock().await = Some(result.clone());
+    result
+}
+
+async fn fetch_upstream(
+    global_state: &GlobalState,
+    context: &UpdateContext,
+) -> Result<(), FetchUpdateStatus> {
+    let player_url = context.source.discover(global_state).await?;
+    info!(
+        "Found player URL {} (id {}) using {}",
+        player_url.url, player_url.id, player_url.source
+    );
+    update_players(global_state, context, &player_url).await
+}
+
+/// Updates the players of all variants to the player at `player_url`
+async fn update_players(
+    global_state: &GlobalState,
+    context: &UpdateContext,
+    player_url: &PlayerUrl,
+) -> Result<(), FetchUpdateStatus> {
+    let variants: Vec<(String, Arc<Mutex<PlayerInfo>>)> = global_state
+        .players
+        .read()
+        .await
+        .variants
+        .iter()
+        .map(|(name, player)| (name.clone(), player.clone()))
+        .collect();
+
+    let mut updated = false;
+    let mut failure = None;
+    for (variant, player) in variants {
+        let config = global_state.config.read().await;
+        let player_path = match config.uses_discovered_url(&variant) {
+            true => None,
+            // checked when the configuration was loaded
+            false => Some(config.player_path(&variant).unwrap_or_default()),
+        };
+        drop(config);
+        let player_js_url: String = match context.source.player_js_url(player_url, player_path.as_deref()) {
+            Some(x) => x,
+            None => {
+                error!("Could not build the player URL of variant {} from {}", variant, player_url.url);
+                failure.get_or_insert(FetchUpdateStatus::CannotBuildPlayerURL);
+                continue;
+            }
+        };
+        match update_variant(context, &variant, &player, player_url, player_js_url).await {
+            Ok(()) => {
+                info!("Updated player variant {}", variant);
+                updated = true;
+            }
+            Err(FetchUpdateStatus::PlayerAlreadyUpdated) => {}
+            Err(x) => {
+                error!("Could not update player variant {}: {:?}", variant, x);
+                failure.get_or_insert(x);
+            }
+        }
+    }
+
+    match failure {
+        Some(x) => Err(x),
+        None if updated => Ok(()),
+        None => Err(FetchUpdateStatus::PlayerAlreadyUpdated),
+    }
+}
+
+async fn update_variant(
+    context: &UpdateContext,
+    variant: &str,
+    player: &Mutex<PlayerInfo>,
+    player_url: &PlayerUrl,
+    player_js_url: String,
+) -> Result<(), FetchUpdateStatus> {
+    let player_id = player_url.numeric_id();
+    let source = &player_url.source;
+    let mut current_player_info = player.lock().await;
+
+    // only local files can change without getting a new URL
+    if context.source.is_remote() && player_js_url == current_player_info.player_url {
+        current_player_info.last_update = SystemTime::now();
+        return Err(FetchUpdateStatus::PlayerAlreadyUpdated);
+    }
+    // release the mutex for other tasks
+    drop(current_player_info);
+
+    // Download the player script
+    info!("Fetching player JS URL: {}", player_js_url);
+    let player_javascript = context.source.fetch_player_js(&player_js_url).await?;
+
+    let mut current_player_info = player.lock().await;
+    if current_player_info.has_player != 0x00 && *current_player_info.player_js == player_javascript {
+        debug!(
+            "Player JS at {} is identical to the current one, skipped extracting {} bytes",
+            player_js_url,
+            player_javascript.len()
+        );
+        current_player_info.player_id = player_id;
+        current_player_info.player_url = player_js_url;
+        current_player_info.discovery_source = source.to_string();
+        current_player_info.last_update = SystemTime::now();
+        return Err(FetchUpdateStatus::PlayerAlreadyUpdated);
+    }
+    drop(current_player_info);
+
+    let player_javascript = Arc::new(player_javascript);
+    let extracted = match extract_tested_player(
+        player_javascript.clone(),
+        context.patterns.clone(),
+        context.fallbacks,
+    )
+    .await?
+    {
+        (x, None) => x,
+        (_, Some(x)) => {
+            error!("Player {} failed the self-test, keeping the current one: {}", player_js_url, x);
+            return Err(FetchUpdateStatus::SelfTestFailed);
+        }
+    };
+
+    let mut current_player_info = player.lock().await;
+    current_player_info.keep_as_previous();
     current_player_info.player_id = player_id;
-    current_player_info.nsig_function_code = nsig_function_code;
-    current_player_info.sig_function_code = sig_code;
-    current_player_info.sig_function_name = sig_function_name.to_string();
-    current_player_info.signature_timestamp = signature_timestamp;
-    current_player_info.has_player = 0xFF;
+    current_player_info.player_url = player_js_url;
+    current_player_info.discovery_source = source.to_string();
+    current_player_info.player_js = player_javascript.clone();
+    current_player_info.set_functions(extracted);
     current_player_info.last_update = SystemTime::now();
+    let cached = CachedPlayer::from_player_info(&current_player_info);
+    drop(current_player_info);
+
+    if let Some(dir) = &context.cache_dir {
+        if let Err(x) = save_player(dir, variant, &cached, &player_javascript).await {
+            warn!("Could not cache the player of variant {}: {}", variant, x);
+        }
+    }
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Player whose wrapper takes `window` from `this` and reads the document
+    /// while setting up, like the real players do
+    static WINDOW_PLAYER: &str = r#"var _yt_player={};(function(g){var window=this;
+var doc=window.document;
+var Xy=function(a){var b=a.split("");b.reverse();return b.join("")+doc.readyState.length};
+Ab=function(a){var b;(b=a.get("n"))&&(b=Xy(b),a.set("n",b))};
+var c={signatureTimestamp:19876};
+})(_yt_player);
+"#;
+
+    #[test]
+    fn parses_relative_player_url() {
+        let url = PlayerUrl::parse("\\/s\\/player\\/1f8742dc\\/player_es6.vflset\\/en_US\\/base.js");
+        assert_eq!(
+            url.url,
+            "https://www.youtube.com/s/player/1f8742dc/player_es6.vflset/en_US/base.js"
+        );
+        assert_eq!(url.id, "1f8742dc");
+        assert_eq!(url.numeric_id(), 0x1f8742dc);
+        assert_eq!(
+            url.with_path(Some("tv-player-ias.vflset/tv-player-ias.js")).as_deref(),
+            Some("https://www.youtube.com/s/player/1f8742dc/tv-player-ias.vflset/tv-player-ias.js")
+        );
+        assert_eq!(url.with_path(None), Some(url.url.clone()));
+    }
+
+    #[test]
+    fn parses_protocol_relative_player_url() {
+        let url = PlayerUrl::parse("//www.youtube.com/s/player/abc_12/base.js");
+        assert_eq!(url.url, "https://www.youtube.com/s/player/abc_12/base.js");
+        assert_eq!(url.id, "abc_12");
+        // not 8 hex digits
+        assert_eq!(url.numeric_id(), 0);
+    }
+
+    #[test]
+    fn player_url_without_player_directory() {
+        let url = PlayerUrl::parse("https://example.com/base.js");
+        assert_eq!(url.id, "");
+        assert_eq!(url.with_path(Some("base.js")), None);
+    }
+
+    #[test]
+    fn discovers_player_id_in_iframe_api() {
+        let url = PlayerUrl::discover(r#"var a="https:\/\/www.youtube.com\/s\/player\/1f8742dc\/www-widgetapi.vflset"#)
+            .unwrap();
+        assert_eq!(url.id, "1f8742dc");
+        assert_eq!(
+            url.url,
+            format!("https://www.youtube.com/s/player/1f8742dc/{}", DEFAULT_PLAYER_PATH)
+        );
+        assert!(PlayerUrl::discover("<html></html>").is_none());
+    }
+
+    #[tokio::test]
+    async fn full_player_runs_sloppy_code() {
+        let player = extract_full_player(WINDOW_PLAYER, &Patterns::default()).unwrap();
+        assert!(player.full_player);
+        assert!(!player.has_sig_function);
+        assert_eq!(player.signature_timestamp, 19876);
+        self_test(&player).await.unwrap();
+    }
+
+    #[tokio::test(flavor = "multi_thread")]
+    async fn falls_back_to_full_player() {
+        let fallbacks = Fallbacks {
+            nsig_search: false,
+            full_player: true,
+        };
+        let (player, error) = extract_tested_player(
+            Arc::new(WINDOW_PLAYER.to_string()),
+            Arc::new(Patterns::default()),
+            fallbacks,
+        )
+        .await
+        .unwrap();
+        assert!(error.is_none());
+        assert!(player.full_player);
+    }
+}
@@ -0,0 +1,286 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+use log::{debug, info};
+use rquickjs::{async_with, AsyncContext, AsyncRuntime};
+
+use crate::{
+    consts::{
+        NSIG_CANDIDATE_MEMORY_LIMIT, NSIG_CANDIDATE_TIME_LIMIT, NSIG_FUNCTION_NAME,
+        NSIG_SEARCH_TIME_LIMIT, SELF_TEST_NSIG_INPUT, SELF_TEST_SIG_INPUT, SELF_TEST_TIME_LIMIT,
+    },
+    jobs::eval_player_code,
+    player::ExtractedPlayer,
+};
+
+#[derive(Debug)]
+pub enum SelfTestError {
+    Runtime(String),
+    /// The code of the function failed to evaluate
+    Eval(&'static str, String),
+    /// Calling the function failed
+    Call(&'static str, String),
+    /// The function returned something that cannot be a signature
+    Implausible(&'static str, String),
+}
+
+impl std::fmt::Display for SelfTestError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Runtime(x) => write!(f, "could not create a JavaScript runtime: {}", x),
+            Self::Eval(function, x) => write!(f, "{} code failed to evaluate: {}", function, x),
+            Self::Call(function, x) => write!(f, "{} function failed: {}", function, x),
+            Self::Implausible(function, x) => {
+                write!(f, "{} function returned an implausible result '{}'", function, x)
+            }
+        }
+    }
+}
+
+fn is_plausible_nsig(input: &str, output: &str) -> bool {
+    // a failing nsig function returns "enhanced_except_..." or its input
+    output != input
+        && (input.len() / 2..=input.len() * 2).contains(&output.len())
+        && output
+            .chars()
+            .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_')
+}
+
+/// How much `output` looks like the nsig value for `input`, from the share of
+/// URL-safe base64 characters and the similarity of the lengths
+fn nsig_score(input: &str, output: &str) -> Option<f64> {
+    if output == input || output.is_empty() {
+        return None;
+    }
+    let charset = output
+        .chars()
+        .filter(|x| x.is_ascii_alphanumeric() || *x == '-' || *x == '_')
+        .count() as f64
+        / output.chars().count() as f64;
+    let length = 1.0 - (output.len().abs_diff(input.len()) as f64 / input.len() as f64).min(1.0);
+    Some(charset + length)
+}
+
+fn is_plausible_sig(input: &str, output: &str) -> bool {
+    // the sig function only reorders and removes characters
+    output != input
+        && (input.len() / 2..=input.len()).contains(&output.len())
+        && output.chars().all(|x| input.contains(x))
+}
+
+async fn call(
+    context: &AsyncContext,
+    name: &'static str,
+    code: &str,
+    function_name: &str,
+    input: &str,
+) -> Result<String, SelfTestError> {
+    let mut call_string: String = String::new();
+    call_string += function_name;
+    call_string += "(\"";
+    call_string += &input.replace("\"", "\\\"");
+    call_string += "\")";
+
+    async_with!(context => |ctx| {
+        let describe = |x: rquickjs::Error| match x.is_exception() {
+            true => format!("{:?}", ctx.catch().as_exception()),
+            false => x.to_string(),
+        };
+        eval_player_code::<(), &str>(&ctx, code)
+            .map_err(|x| SelfTestError::Eval(name, describe(x)))?;
+        ctx.eval::<String, String>(call_string)
+            .map_err(|x| SelfTestError::Call(name, describe(x)))
+    })
+    .await
+}
+
+/// Runs the extracted functions on sample inputs in a scratch runtime
+pub async fn self_test(player: &ExtractedPlayer) -> Result<(), SelfTestError> {
+    // players without a sig function get a placeholder that cannot be called
+    let sig_function = match player.has_sig_function {
+        true => Some((
+            player.sig_function_name.as_str(),
+            player.sig_function_code.as_str(),
+        )),
+        false => None,
+    };
+    self_test_functions(&player.nsig_function_code, sig_function).await
+}
+
+/// Runs the nsig function and the sig function, given by name and code, on
+/// sample inputs in a scratch runtime
+pub async fn self_test_functions(
+    nsig_function_code: &str,
+    sig_function: Option<(&str, &str)>,
+) -> Result<(), SelfTestError> {
+    let runtime = AsyncRuntime::new().map_err(|x| SelfTestError::Runtime(x.to_string()))?;
+    let deadline = Instant::now() + SELF_TEST_TIME_LIMIT;
+    runtime
+        .set_interrupt_handler(Some(Box::new(move || Instant::now() > deadline)))
+        .await;
+
+    let context = AsyncContext::full(&runtime)
+        .await
+        .map_err(|x| SelfTestError::Runtime(x.to_string()))?;
+    let nsig = call(
+        &context,
+        "nsig",
+        nsig_function_code,
+        NSIG_FUNCTION_NAME,
+        SELF_TEST_NSIG_INPUT,
+    )
+    .await?;
+    debug!("Self-test nsig: {} -> {}", SELF_TEST_NSIG_INPUT, nsig);
+    if !is_plausible_nsig(SELF_TEST_NSIG_INPUT, &nsig) {
+        return Err(SelfTestError::Implausible("nsig", nsig));
+    }
+
+    let (sig_function_name, sig_function_code) = match sig_function {
+        Some(x) => x,
+        None => return Ok(()),
+    };
+    let context = AsyncContext::full(&runtime)
+        .await
+        .map_err(|x| SelfTestError::Runtime(x.to_string()))?;
+    let sig = call(
+        &context,
+        "sig",
+        sig_function_code,
+        sig_function_name,
+        SELF_TEST_SIG_INPUT,
+    )
+    .await?;
+    debug!("Self-test sig: {} -> {}", SELF_TEST_SIG_INPUT, sig);
+    if !is_plausible_sig(SELF_TEST_SIG_INPUT, &sig) {
+        return Err(SelfTestError::Implausible("sig", sig));
+    }
+    Ok(())
+}
+
+/// Runs every nsig code, each defining `decrypt_nsig`, on a sample input in a
+/// fresh context with a time limit and returns the index of the one whose
+/// result looks most like an nsig value. The earlier code wins a tie.
+pub async fn best_nsig_candidate(candidates: &[String]) -> Result<Option<usize>, SelfTestError> {
+    let runtime = AsyncRuntime::new().map_err(|x| SelfTestError::Runtime(x.to_string()))?;
+    runtime.set_memory_limit(NSIG_CANDIDATE_MEMORY_LIMIT).await;
+    let deadline = Arc::new(Mutex::new(Instant::now()));
+    let handler_deadline = deadline.clone();
+    runtime
+        .set_interrupt_handler(Some(Box::new(move || {
+            Instant::now() > *handler_deadline.lock().unwrap()
+        })))
+        .await;
+
+    let search_deadline = Instant::now() + NSIG_SEARCH_TIME_LIMIT;
+    let mut best: Option<(usize, f64)> = None;
+    for (index, code) in candidates.iter().enumerate() {
+        if Instant::now() > search_deadline {
+            info!("nsig search ran out of time after {} candidates", index);
+            break;
+        }
+        *deadline.lock().unwrap() = Instant::now() + NSIG_CANDIDATE_TIME_LIMIT;
+        let context = AsyncContext::full(&runtime)
+            .await
+            .map_err(|x| SelfTestError::Runtime(x.to_string()))?;
+        let output = match call(&context, "nsig", code, NSIG_FUNCTION_NAME, SELF_TEST_NSIG_INPUT).await {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let score = match nsig_score(SELF_TEST_NSIG_INPUT, &output) {
+            Some(x) if is_plausible_nsig(SELF_TEST_NSIG_INPUT, &output) => x,
+            _ => continue,
+        };
+        debug!("nsig candidate {}: {} -> {}, score {:.2}", index, SELF_TEST_NSIG_INPUT, output, score);
+        if best.is_none_or(|(_, x)| score > x) {
+            best = Some((index, score));
+        }
+    }
+    Ok(best.map(|(index, _)| index))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn recognizes_plausible_nsig() {
+        assert!(is_plausible_nsig(SELF_TEST_NSIG_INPUT, "qOfKsC3-gHB_Jw"));
+        assert!(is_plausible_nsig(SELF_TEST_NSIG_INPUT, "ehBVqzskpiMkZwyHi"));
+    }
+
+    #[test]
+    fn rejects_implausible_nsig() {
+        // the input itself, the error result and lengths far off the input's
+        assert!(!is_plausible_nsig(SELF_TEST_NSIG_INPUT, SELF_TEST_NSIG_INPUT));
+        assert!(!is_plausible_nsig(
+            SELF_TEST_NSIG_INPUT,
+            "enhanced_except_gZYB_n1M_w8_iHywZkMipkszqVBhe"
+        ));
+        assert!(!is_plausible_nsig(SELF_TEST_NSIG_INPUT, "abc"));
+        assert!(!is_plausible_nsig(SELF_TEST_NSIG_INPUT, "qOfKsC3 gHB/Jw"));
+    }
+
+    #[test]
+    fn recognizes_plausible_sig() {
+        let reversed: String = SELF_TEST_SIG_INPUT.chars().rev().collect();
+        assert!(is_plausible_sig(SELF_TEST_SIG_INPUT, &reversed));
+        assert!(is_plausible_sig(SELF_TEST_SIG_INPUT, &SELF_TEST_SIG_INPUT[3..]));
+    }
+
+    #[test]
+    fn rejects_implausible_sig() {
+        // the input itself, added characters, a longer and a much shorter result
+        assert!(!is_plausible_sig(SELF_TEST_SIG_INPUT, SELF_TEST_SIG_INPUT));
+        let foreign = format!("{}#", &SELF_TEST_SIG_INPUT[1..]);
+        assert!(!is_plausible_sig(SELF_TEST_SIG_INPUT, &foreign));
+        let longer = format!("{}A", SELF_TEST_SIG_INPUT);
+        assert!(!is_plausible_sig(SELF_TEST_SIG_INPUT, &longer));
+        assert!(!is_plausible_sig(SELF_TEST_SIG_INPUT, &SELF_TEST_SIG_INPUT[..10]));
+    }
+
+    #[tokio::test]
+    async fn self_test_rejects_identity_nsig() {
+        let identity = format!("function {}(a){{return a}}", NSIG_FUNCTION_NAME);
+        let reverse = format!(
+            "function {}(a){{return a.split('').reverse().join('')}}",
+            NSIG_FUNCTION_NAME
+        );
+        assert!(matches!(
+            self_test_functions(&identity, None).await,
+            Err(SelfTestError::Implausible("nsig", _))
+        ));
+        assert!(self_test_functions(&reverse, None).await.is_ok());
+    }
+
+    #[tokio::test]
+    async fn nsig_candidates_skip_identity_and_endless_functions() {
+        let candidates = [
+            format!("function {}(a){{return a}}", NSIG_FUNCTION_NAME),
+            format!("function {}(a){{while(true){{}}}}", NSIG_FUNCTION_NAME),
+            format!("function {}(a){{throw Error(a)}}", NSIG_FUNCTION_NAME),
+            format!(
+                "function {}(a){{return a.split('').reverse().join('')}}",
+                NSIG_FUNCTION_NAME
+            ),
+        ];
+        let start = Instant::now();
+        assert_eq!(best_nsig_candidate(&candidates).await.unwrap(), Some(3));
+        // the endless function was interrupted at its own time limit
+        assert!(start.elapsed() < SELF_TEST_TIME_LIMIT);
+        assert_eq!(best_nsig_candidate(&candidates[..3]).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn nsig_candidates_prefer_the_most_plausible_result() {
+        let candidates = [
+            format!("function {}(a){{return a.slice(0,10)}}", NSIG_FUNCTION_NAME),
+            format!(
+                "function {}(a){{return a.split('').reverse().join('')}}",
+                NSIG_FUNCTION_NAME
+            ),
+            format!("function {}(a){{return a.slice(1)}}", NSIG_FUNCTION_NAME),
+        ];
+        assert_eq!(best_nsig_candidate(&candidates).await.unwrap(), Some(1));
+    }
+}
@@ -0,0 +1,282 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use log::{error, warn};
+use tokio::fs;
+
+use crate::{
+    config::DiscoveryStrategy,
+    consts::YOUTUBE_IFRAME_API,
+    jobs::GlobalState,
+    player::{FetchUpdateStatus, PlayerUrl},
+    upstream::UpstreamClient,
+};
+
+/// Where the player JS comes from
+pub enum PlayerSource {
+    /// Discovered on YouTube and downloaded
+    Http {
+        upstream: Arc<UpstreamClient>,
+        retries: u32,
+        retry_delay: Duration,
+    },
+    /// A local player JS, or a directory of `{player_id}/base.js`
+    Files(PathBuf),
+}
+
+impl PlayerSource {
+    pub async fn from_state(state: &GlobalState) -> PlayerSource {
+        if let Some(path) = &state.player_file {
+            return PlayerSource::Files(path.clone());
+        }
+        let config = state.config.read().await;
+        PlayerSource::Http {
+            upstream: state.upstream.read().await.clone(),
+            retries: config.fetch_retries(),
+            retry_delay: config.fetch_retry_delay(),
+        }
+    }
+
+    /// Whether updates count against `upstream_cooldown`
+    pub fn is_remote(&self) -> bool {
+        matches!(self, PlayerSource::Http { .. })
+    }
+
+    /// Finds the current player
+    pub async fn discover(&self, state: &GlobalState) -> Result<PlayerUrl, FetchUpdateStatus> {
+        match self {
+            PlayerSource::Http {
+                upstream,
+                retries,
+                retry_delay,
+            } => discover_remote(state, upstream, *retries, *retry_delay).await,
+            PlayerSource::Files(path) => {
+                let static_player_id = state.config.read().await.static_player_id.clone();
+                discover_local(path, static_player_id.as_deref()).await
+            }
+        }
+    }
+
+    /// URL of the player JS of a variant, see `PlayerUrl::with_path`. Local
+    /// players have no variants, every variant gets the same file.
+    pub fn player_js_url(&self, player_url: &PlayerUrl, path: Option<&str>) -> Option<String> {
+        match self {
+            PlayerSource::Http { .. } => player_url.with_path(path),
+            PlayerSource::Files(_) => Some(player_url.url.clone()),
+        }
+    }
+
+    /// The player with `player_id`, `None` if the id is not valid
+    pub fn player_url_for_id(&self, player_id: &str) -> Option<PlayerUrl> {
+        let is_valid = !player_id.is_empty()
+            && player_id
+                .chars()
+                .all(|x| x.is_ascii_alphanumeric() || x == '-' || x == '_');
+        if !is_valid {
+            return None;
+        }
+        let mut player_url = match self {
+            PlayerSource::Http { .. } => PlayerUrl::from_id(player_id),
+            PlayerSource::Files(path) if path.is_dir() => {
+                PlayerUrl::local(&path.join(player_id).join("base.js"))
+            }
+            PlayerSource::Files(path) => PlayerUrl::local(path),
+        };
+        player_url.source = "pinned".to_string();
+        Some(player_url)
+    }
+
+    pub async fn fetch_player_js(&self, url: &str) -> Result<String, FetchUpdateStatus> {
+        let result = match self {
+            PlayerSource::Http {
+                upstream,
+                retries,
+                retry_delay,
+            } => upstream
+                .get_text_uncached(url, *retries, *retry_delay)
+                .await
+                .map_err(|x| x.to_string()),
+            PlayerSource::Files(_) => fs::read_to_string(url).await.map_err(|x| x.to_string()),
+        };
+        result.map_err(|x| {
+            error!("Could not fetch the player JS: {}", x);
+            FetchUpdateStatus::CannotFetchPlayerJS
+        })
+    }
+}
+
+/// Tries the configured discovery strategies in order until one finds the player
+async fn discover_remote(
+    state: &GlobalState,
+    upstream: &UpstreamClient,
+    retries: u32,
+    retry_delay: Duration,
+) -> Result<PlayerUrl, FetchUpdateStatus> {
+    let config = state.config.read().await;
+    let mut sources: Vec<(DiscoveryStrategy, String)> = Vec::new();
+    for strategy in config.discovery_strategies() {
+        match strategy {
+            DiscoveryStrategy::Watch => {
+                for url in config.watch_urls() {
+                    sources.push((strategy, url));
+                }
+            }
+            DiscoveryStrategy::IframeApi => {
+                sources.push((strategy, YOUTUBE_IFRAME_API.to_string()))
+            }
+            DiscoveryStrategy::Embed => sources.push((strategy, config.embed_url().to_string())),
+            DiscoveryStrategy::Static => {
+                if let Some(id) = &config.static_player_id {
+                    sources.push((strategy, id.clone()));
+                }
+            }
+        }
+    }
+    drop(config);
+
+    let mut status = FetchUpdateStatus::CannotMatchPlayerID;
+    for (strategy, source) in sources {
+        let mut player_url = match strategy {
+            DiscoveryStrategy::Static => PlayerUrl::from_id(&source),
+            _ => {
+                let page = match upstream.get_text(&source, retries, retry_delay).await {
+                    Ok(x) => x,
+                    Err(x) => {
+                        warn!("Could not fetch {} ({}): {}", source, strategy, x);
+                        status = FetchUpdateStatus::CannotFetchTestVideo;
+                        continue;
+                    }
+                };
+                match PlayerUrl::discover(&page) {
+                    Some(x) => x,
+                    None => {
+                        warn!("No player found on {} ({})", source, strategy);
+                        status = FetchUpdateStatus::CannotMatchPlayerID;
+                        continue;
+                    }
+                }
+            }
+        };
+        player_url.source = strategy.to_string();
+        return Ok(player_url);
+    }
+
+    error!("Could not discover the player with any strategy");
+    Err(status)
+}
+
+/// Uses `path` if it is a file, otherwise `{static_player_id}/base.js` below
+/// it or the most recently modified `{player_id}/base.js`
+async fn discover_local(
+    path: &Path,
+    static_player_id: Option<&str>,
+) -> Result<PlayerUrl, FetchUpdateStatus> {
+    let metadata = fs::metadata(path).await.map_err(|x| {
+        error!("Could not read {}: {}", path.display(), x);
+        FetchUpdateStatus::CannotFetchPlayerJS
+    })?;
+    if !metadata.is_dir() {
+        return Ok(PlayerUrl::local(path));
+    }
+
+    if let Some(id) = static_player_id {
+        return Ok(PlayerUrl::local(&path.join(id).join("base.js")));
+    }
+
+    let mut newest: Option<(SystemTime, PathBuf)> = None;
+    let mut entries = fs::read_dir(path).await.map_err(|x| {
+        error!("Could not read {}: {}", path.display(), x);
+        FetchUpdateStatus::CannotFetchPlayerJS
+    })?;
+    while let Ok(Some(entry)) = entries.next_entry().await {
+        let player_js = entry.path().join("base.js");
+        let modified = match fs::metadata(&player_js).await.and_then(|x| x.modified()) {
+            Ok(x) => x,
+            Err(_) => continue,
+        };
+        let is_newer = match &newest {
+            Some((x, _)) => modified > *x,
+            None => true,
+        };
+        if is_newer {
+            newest = Some((modified, player_js));
+        }
+    }
+    match newest {
+        Some((_, player_js)) => Ok(PlayerUrl::local(&player_js)),
+        None => {
+            error!("No {{player_id}}/base.js found in {}", path.display());
+            Err(FetchUpdateStatus::CannotMatchPlayerID)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::File;
+
+    fn player_dir(name: &str) -> PathBuf {
+        let path = std::env::temp_dir().join(format!(
+            "inv_sig_helper_players_{}_{}",
+            std::process::id(),
+            name
+        ));
+        let _ = std::fs::remove_dir_all(&path);
+        std::fs::create_dir_all(&path).unwrap();
+        path
+    }
+
+    fn add_player(dir: &Path, id: &str, age: u64) -> PathBuf {
+        let player_js = dir.join(id).join("base.js");
+        std::fs::create_dir_all(player_js.parent().unwrap()).unwrap();
+        let file = File::create(&player_js).unwrap();
+        file.set_modified(SystemTime::now() - Duration::from_secs(age))
+            .unwrap();
+        player_js
+    }
+
+    #[tokio::test]
+    async fn discovers_newest_local_player() {
+        let dir = player_dir("newest");
+        add_player(&dir, "aaaa1111", 300);
+        let newest = add_player(&dir, "bbbb2222", 10);
+        add_player(&dir, "cccc3333", 200);
+        // directories without a player are skipped
+        std::fs::create_dir_all(dir.join("empty")).unwrap();
+
+        let player_url = discover_local(&dir, None).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(player_url.url, newest.to_string_lossy());
+        assert_eq!(player_url.id, "bbbb2222");
+    }
+
+    #[tokio::test]
+    async fn static_player_id_overrides_newest_local_player() {
+        let dir = player_dir("static");
+        let pinned = add_player(&dir, "aaaa1111", 300);
+        add_player(&dir, "bbbb2222", 10);
+
+        let player_url = discover_local(&dir, Some("aaaa1111")).await.unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(player_url.url, pinned.to_string_lossy());
+        assert_eq!(player_url.id, "aaaa1111");
+    }
+
+    #[tokio::test]
+    async fn local_file_is_used_as_is() {
+        let dir = player_dir("file");
+        let player_js = add_player(&dir, "aaaa1111", 0);
+        let player_url = discover_local(&player_js, Some("bbbb2222")).await.unwrap();
+        assert_eq!(player_url.url, player_js.to_string_lossy());
+
+        std::fs::remove_dir_all(dir.join("aaaa1111")).unwrap();
+        assert!(matches!(
+            discover_local(&dir, None).await,
+            Err(FetchUpdateStatus::CannotMatchPlayerID)
+        ));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,137 @@
+use std::{
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use log::{debug, info, warn};
+use tokio::time::sleep;
+
+use crate::{
+    config::Config,
+    jobs::GlobalState,
+    player::{fetch_update, FetchUpdateStatus},
+};
+
+// good enough to spread out the updates of several instances
+fn random_delay(max: Duration) -> Duration {
+    let max_millis = max.as_millis() as u64;
+    if max_millis == 0 {
+        return Duration::ZERO;
+    }
+    let nanos = SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .unwrap()
+        .subsec_nanos() as u64;
+    Duration::from_millis(nanos % max_millis)
+}
+
+fn next_delay(config: &Config, failures: u32) -> Option<Duration> {
+    let interval = config.update_interval()?;
+
+    if failures == 0 {
+        return Some(interval + random_delay(config.update_jitter()));
+    }
+    let backoff = config
+        .update_retry_delay()
+        .checked_mul(1u32.checked_shl(failures - 1).unwrap_or(u32::MAX))
+        .unwrap_or(interval);
+    Some(backoff.min(interval))
+}
+
+pub async fn run_update_scheduler(state: Arc<GlobalState>) {
+    // retry soon if the initial fetch did not get a player
+    let mut failures: u32 = match state.player(None).await {
+        Some((_, player)) => match player.lock().await.has_player {
+            0x00 => 1,
+            _ => 0,
+        },
+        None => {
+            warn!("The default player variant is missing, updating at the next interval");
+            0
+        }
+    };
+
+    loop {
+        let delay = next_delay(&*state.config.read().await, failures);
+        *state.next_update.lock().await = delay.map(|x| SystemTime::now() + x);
+
+        match delay {
+            Some(x) => {
+                debug!("Next automatic player update in {} seconds", x.as_secs());
+                tokio::select! {
+                    _ = sleep(x) => {}
+                    _ = state.config_changed.notified() => continue,
+                }
+            }
+            None => {
+                debug!("Automatic player updates are disabled");
+                state.config_changed.notified().await;
+                continue;
+            }
+        }
+
+        info!("Running scheduled player update");
+        match fetch_update(state.clone()).await {
+            Ok(()) => {
+                info!("Successfully fetched player");
+                failures = 0;
+            }
+            Err(FetchUpdateStatus::PlayerAlreadyUpdated) => failures = 0,
+            Err(FetchUpdateStatus::PlayerPinned) => {
+                info!("Player is pinned, skipped the scheduled update");
+                failures = 0;
+            }
+            Err(x) => {
+                failures = failures.saturating_add(1);
+                warn!(
+                    "Scheduled player update failed ({} in a row): {:?}",
+                    failures, x
+                );
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn update_config(interval: u64, jitter: u64, retry_delay: u64) -> Config {
+        Config {
+            update_interval: Some(interval),
+            update_jitter: Some(jitter),
+            update_retry_delay: Some(retry_delay),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn jitter_stays_below_its_bound() {
+        let config = update_config(3600, 60, 30);
+        for _ in 0..100 {
+            let delay = next_delay(&config, 0).unwrap();
+            assert!(delay >= Duration::from_secs(3600));
+            assert!(delay < Duration::from_secs(3660));
+        }
+        assert_eq!(
+            next_delay(&update_config(3600, 0, 30), 0),
+            Some(Duration::from_secs(3600))
+        );
+    }
+
+    #[test]
+    fn backoff_is_capped_at_the_interval() {
+        let config = update_config(3600, 60, 30);
+        assert_eq!(next_delay(&config, 1), Some(Duration::from_secs(30)));
+        assert_eq!(next_delay(&config, 2), Some(Duration::from_secs(60)));
+        assert_eq!(next_delay(&config, 3), Some(Duration::from_secs(120)));
+        assert_eq!(next_delay(&config, 8), Some(Duration::from_secs(3600)));
+        assert_eq!(next_delay(&config, 40), Some(Duration::from_secs(3600)));
+        assert_eq!(next_delay(&config, u32::MAX), Some(Duration::from_secs(3600)));
+    }
+
+    #[test]
+    fn zero_interval_disables_updates() {
+        assert_eq!(next_delay(&update_config(0, 60, 30), 0), None);
+        assert_eq!(next_delay(&update_config(0, 60, 30), 3), None);
+    }
+}
@@ -0,0 +1,398 @@
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+use log::{debug, info, warn};
+use reqwest::{
+    cookie::Jar,
+    header::{
+        HeaderMap, HeaderName, HeaderValue, ACCEPT_LANGUAGE, ETAG, IF_MODIFIED_SINCE,
+        IF_NONE_MATCH, LAST_MODIFIED,
+    },
+    Client, Proxy, Response, StatusCode, Url,
+};
+use tokio::{sync::Mutex, time::sleep};
+
+use crate::{
+    config::Config,
+    consts::{DEFAULT_ACCEPT_LANGUAGE, MAX_CACHED_RESPONSES, YOUTUBE_URL},
+};
+
+#[derive(Debug)]
+pub enum UpstreamError {
+    Http(reqwest::Error),
+    InvalidHeader(String),
+    ResponseTooLarge(usize),
+    /// `304 Not Modified` to a request without conditional headers
+    UnexpectedNotModified,
+}
+
+impl std::fmt::Display for UpstreamError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Http(x) => write!(f, "{}", x),
+            Self::InvalidHeader(x) => write!(f, "invalid header '{}'", x),
+            Self::ResponseTooLarge(x) => write!(f, "response is larger than {} bytes", x),
+            Self::UnexpectedNotModified => write!(f, "not modified, but there is no cached response"),
+        }
+    }
+}
+
+impl UpstreamError {
+    fn is_transient(&self) -> bool {
+        match self {
+            Self::Http(x) => match x.status() {
+                Some(status) => {
+                    status.is_server_error() || status == StatusCode::TOO_MANY_REQUESTS
+                }
+                None => x.is_timeout() || x.is_connect() || x.is_request() || x.is_body(),
+            },
+            _ => false,
+        }
+    }
+}
+
+/// A response kept to answer conditional requests
+struct CachedResponse {
+    etag: Option<HeaderValue>,
+    last_modified: Option<HeaderValue>,
+    body: String,
+}
+
+/// HTTP clients used for every request to YouTube, one per configured proxy
+pub struct UpstreamClient {
+    clients: Vec<Client>,
+    current: AtomicUsize,
+    max_response_size: usize,
+    /// Discovery pages that had an `ETag` or `Last-Modified` header, by URL
+    cache: Mutex<HashMap<String, CachedResponse>>,
+}
+
+fn default_headers(config: &Config) -> Result<HeaderMap, UpstreamError> {
+    let mut headers = HeaderMap::new();
+    headers.insert(
+        ACCEPT_LANGUAGE,
+        HeaderValue::from_static(DEFAULT_ACCEPT_LANGUAGE),
+    );
+    for (name, value) in &config.headers {
+        let header_name = HeaderName::from_bytes(name.as_bytes())
+            .map_err(|_| UpstreamError::InvalidHeader(name.clone()))?;
+        let header_value =
+            HeaderValue::from_str(value).map_err(|_| UpstreamError::InvalidHeader(name.clone()))?;
+        headers.insert(header_name, header_value);
+    }
+    Ok(headers)
+}
+
+fn cookie_jar(config: &Config) -> Jar {
+    let jar = Jar::default();
+    let url: Url = YOUTUBE_URL.parse().unwrap();
+    for (name, value) in config.cookies() {
+        jar.add_cookie_str(
+            &format!("{}={}; Domain=.youtube.com; Path=/", name, value),
+            &url,
+        );
+    }
+    jar
+}
+
+async fn read_body(mut response: Response, limit: usize) -> Result<String, UpstreamError> {
+    if response.content_length().is_some_and(|x| x > limit as u64) {
+        return Err(UpstreamError::ResponseTooLarge(limit));
+    }
+    let mut body: Vec<u8> = Vec::new();
+    while let Some(chunk) = response.chunk().await.map_err(UpstreamError::Http)? {
+        if body.len() + chunk.len() > limit {
+            return Err(UpstreamError::ResponseTooLarge(limit));
+        }
+        body.extend_from_slice(&chunk);
+    }
+    Ok(String::from_utf8_lossy(&body).into_owned())
+}
+
+impl UpstreamClient {
+    pub fn new(config: &Config) -> Result<UpstreamClient, UpstreamError> {
+        let headers = default_headers(config)?;
+        let jar = Arc::new(cookie_jar(config));
+        let builder = || {
+            Client::builder()
+                .connect_timeout(config.connect_timeout())
+                .read_timeout(config.read_timeout())
+                .user_agent(config.user_agent())
+                .default_headers(headers.clone())
+                .cookie_provider(jar.clone())
+        };
+
+        let mut clients = Vec::with_capacity(config.proxies.len().max(1));
+        if config.proxies.is_empty() {
+            clients.push(builder().build().map_err(UpstreamError::Http)?);
+        }
+        for proxy in &config.proxies {
+            let proxy = Proxy::all(proxy).map_err(UpstreamError::Http)?;
+            clients.push(builder().proxy(proxy).build().map_err(UpstreamError::Http)?);
+        }
+        Ok(UpstreamClient {
+            clients,
+            current: AtomicUsize::new(0),
+            max_response_size: config.max_response_size(),
+            cache: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Switches to the next proxy, if there is more than one
+    fn rotate(&self, failed: usize) {
+        if self.clients.len() < 2 {
+            return;
+        }
+        let next = (failed + 1) % self.clients.len();
+        // only rotate once if several requests failed on the same proxy
+        if self
+            .current
+            .compare_exchange(failed, next, Ordering::Relaxed, Ordering::Relaxed)
+            .is_ok()
+        {
+            info!("Switching to proxy #{}", next);
+        }
+    }
+
+    async fn get_text_once(
+        &self,
+        client: &Client,
+        url: &str,
+        use_cache: bool,
+    ) -> Result<String, UpstreamError> {
+        // whether the cached validators may still be sent
+        let mut revalidate = use_cache;
+        let response = loop {
+            let mut request = client.get(url);
+            let mut conditional = false;
+            if let Some(cached) = self.cache.lock().await.get(url).filter(|_| revalidate) {
+                if let Some(etag) = &cached.etag {
+                    request = request.header(IF_NONE_MATCH, etag.clone());
+                }
+                if let Some(last_modified) = &cached.last_modified {
+                    request = request.header(IF_MODIFIED_SINCE, last_modified.clone());
+                }
+                conditional = true;
+            }
+
+            let response = request.send().await.map_err(UpstreamError::Http)?;
+            if response.status() != StatusCode::NOT_MODIFIED {
+                break response;
+            }
+            if !conditional {
+                return Err(UpstreamError::UnexpectedNotModified);
+            }
+            if let Some(cached) = self.cache.lock().await.get(url) {
+                debug!("{} was not modified, saved {} bytes", url, cached.body.len());
+                return Ok(cached.body.clone());
+            }
+            // the cache was cleared since the request was sent
+            debug!("{} was not modified but is no longer cached, requesting it again", url);
+            revalidate = false;
+        };
+        let response = response.error_for_status().map_err(UpstreamError::Http)?;
+
+        let etag = response.headers().get(ETAG).cloned();
+        let last_modified = response.headers().get(LAST_MODIFIED).cloned();
+        let body = read_body(response, self.max_response_size).await?;
+        if use_cache && (etag.is_some() || last_modified.is_some()) {
+            let mut cache = self.cache.lock().await;
+            // keeps the cache bounded if the configured pages change
+            if cache.len() >= MAX_CACHED_RESPONSES {
+                cache.clear();
+            }
+            cache.insert(
+                url.to_string(),
+                CachedResponse {
+                    etag,
+                    last_modified,
+                    body: body.clone(),
+                },
+            );
+        }
+        Ok(body)
+    }
+
+    /// Downloads a page, with a conditional request if it was downloaded before
+    pub async fn get_text(
+        &self,
+        url: &str,
+        retries: u32,
+        retry_delay: Duration,
+    ) -> Result<String, UpstreamError> {
+        self.get_text_retrying(url, retries, retry_delay, true).await
+    }
+
+    /// Downloads a page without keeping it, for the player JS: it is kept by
+    /// the player and only downloaded again from a new URL
+    pub async fn get_text_uncached(
+        &self,
+        url: &str,
+        retries: u32,
+        retry_delay: Duration,
+    ) -> Result<String, UpstreamError> {
+        self.get_text_retrying(url, retries, retry_delay, false).await
+    }
+
+    async fn get_text_retrying(
+        &self,
+        url: &str,
+        retries: u32,
+        retry_delay: Duration,
+        use_cache: bool,
+    ) -> Result<String, UpstreamError> {
+        let mut attempt: u32 = 0;
+        loop {
+            let index = self.current.load(Ordering::Relaxed);
+            let result = self.get_text_once(&self.clients[index], url, use_cache).await;
+            match result {
+                Ok(x) => return Ok(x),
+                Err(x) => {
+                    self.rotate(index);
+                    // a failing proxy is worth retrying through the next one
+                    if attempt >= retries || !(x.is_transient() || self.clients.len() > 1) {
+                        return Err(x);
+                    }
+                    let delay = retry_delay
+                        .checked_mul(1u32.checked_shl(attempt).unwrap_or(u32::MAX))
+                        .unwrap_or(Duration::MAX);
+                    warn!(
+                        "Request to {} failed, retrying in {} ms: {}",
+                        url,
+                        delay.as_millis(),
+                        x
+                    );
+                    sleep(delay).await;
+                    attempt += 1;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+        sync::{mpsc, oneshot},
+    };
+
+    use super::*;
+
+    /// Serves one scripted response per connection. Every request head is
+    /// sent to the test, which answers with `()` to let the response go out.
+    async fn serve(
+        responses: Vec<&'static str>,
+    ) -> (String, mpsc::UnboundedReceiver<(String, oneshot::Sender<()>)>) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("http://{}/iframe_api", listener.local_addr().unwrap());
+        let (sender, receiver) = mpsc::unbounded_channel();
+        tokio::spawn(async move {
+            for response in responses {
+                let (mut socket, _) = listener.accept().await.unwrap();
+                let mut head = Vec::new();
+                let mut buffer = [0; 1024];
+                while !head.ends_with(b"\r\n\r\n") {
+                    let size = socket.read(&mut buffer).await.unwrap();
+                    head.extend_from_slice(&buffer[..size]);
+                }
+                let (release, released) = oneshot::channel();
+                let _ = sender.send((String::from_utf8_lossy(&head).to_lowercase(), release));
+                let _ = released.await;
+                socket.write_all(response.as_bytes()).await.unwrap();
+            }
+        });
+        (url, receiver)
+    }
+
+    /// Answers the next request and returns its head
+    async fn next_request(
+        requests: &mut mpsc::UnboundedReceiver<(String, oneshot::Sender<()>)>,
+    ) -> String {
+        let (head, release) = requests.recv().await.unwrap();
+        release.send(()).unwrap();
+        head
+    }
+
+    static OK: &str = "HTTP/1.1 200 OK\r\nETag: \"v1\"\r\nContent-Length: 4\r\nConnection: close\r\n\r\npage";
+    static NOT_MODIFIED: &str = "HTTP/1.1 304 Not Modified\r\nETag: \"v1\"\r\nConnection: close\r\n\r\n";
+
+    fn client() -> UpstreamClient {
+        UpstreamClient::new(&Config::default()).unwrap()
+    }
+
+    #[tokio::test]
+    async fn not_modified_returns_cached_page() {
+        let (url, mut requests) = serve(vec![OK, NOT_MODIFIED]).await;
+        let client = client();
+        let (body, head) = tokio::join!(
+            client.get_text(&url, 0, Duration::ZERO),
+            next_request(&mut requests)
+        );
+        assert_eq!(body.unwrap(), "page");
+        assert!(!head.contains("if-none-match"));
+        let (body, head) = tokio::join!(
+            client.get_text(&url, 0, Duration::ZERO),
+            next_request(&mut requests)
+        );
+        assert_eq!(body.unwrap(), "page");
+        assert!(head.contains("if-none-match: \"v1\""));
+    }
+
+    #[tokio::test]
+    async fn not_modified_after_eviction_requests_again() {
+        let (url, mut requests) = serve(vec![OK, NOT_MODIFIED, OK]).await;
+        let client = client();
+        let (body, _) = tokio::join!(
+            client.get_text(&url, 0, Duration::ZERO),
+            next_request(&mut requests)
+        );
+        body.unwrap();
+        // the page is evicted while the conditional request is answered
+        let evict = async {
+            let (head, release) = requests.recv().await.unwrap();
+            assert!(head.contains("if-none-match"));
+            client.cache.lock().await.clear();
+            release.send(()).unwrap();
+            next_request(&mut requests).await
+        };
+        let (body, head) = tokio::join!(client.get_text(&url, 0, Duration::ZERO), evict);
+        assert_eq!(body.unwrap(), "page");
+        assert!(!head.contains("if-none-match"));
+    }
+
+    #[tokio::test]
+    async fn unexpected_not_modified_is_an_error() {
+        let (url, mut requests) = serve(vec![NOT_MODIFIED]).await;
+        let client = client();
+        let (result, _) = tokio::join!(
+            client.get_text(&url, 0, Duration::ZERO),
+            next_request(&mut requests)
+        );
+        assert!(matches!(result, Err(UpstreamError::UnexpectedNotModified)));
+    }
+
+    #[tokio::test]
+    async fn player_js_is_not_cached() {
+        let (url, mut requests) = serve(vec![OK, OK]).await;
+        let client = client();
+        let (body, _) = tokio::join!(
+            client.get_text_uncached(&url, 0, Duration::ZERO),
+            next_request(&mut requests)
+        );
+        body.unwrap();
+        assert!(client.cache.lock().await.is_empty());
+        let (body, head) = tokio::join!(
+            client.get_text_uncached(&url, 0, Duration::ZERO),
+            next_request(&mut requests)
+        );
+        body.unwrap();
+        assert!(!head.contains("if-none-match"));
+    }
+}